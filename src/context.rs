@@ -6,7 +6,24 @@ pub struct Context {
     pub font                    : Option<Font>,
 
     pub iso_state               : bool,
+    /// Path trace instead of the flat preview
     pub render_state            : bool,
+
+    /// Light the preview, otherwise it shows the flat palette colors
+    pub shading                 : bool,
+    /// The direction towards the sun
+    pub sun                     : Vec3f,
+    pub shadows                 : bool,
+    pub ao_state                : bool,
+    /// The dithering of the path tracer output when it is clipped to the palette
+    pub dither                  : Dither,
+
+    /// Indexed by the material byte of the voxels
+    pub materials               : Vec<Material>,
+    pub light                   : Light,
+
+    /// The number of accumulated render passes
+    pub samples                 : i32,
 }
 
 impl Context {
@@ -27,7 +44,9 @@ impl Context {
             if name == "aurora.txt" {
                 if let Some(bytes) = Embedded::get(name) {
                     if let Some(string) = std::str::from_utf8(bytes.data.as_ref()).ok() {
-                        palette.load_from_txt(string.to_string())
+                        palette.load_from_txt(string.to_string());
+                        palette.build_tree();
+                        palette.name = name.to_string();
                     }
                 }
             }
@@ -39,6 +58,32 @@ impl Context {
 
             iso_state           : false,
             render_state        : false,
+
+            shading             : true,
+            sun                 : vec3f(0.5, 1.0, 0.7),
+            shadows             : true,
+            ao_state            : true,
+            dither              : Dither::None,
+
+            materials           : vec![Material::new(); MATERIAL_COUNT],
+            light               : Light::new(),
+
+            samples             : 10,
         }
     }
-}
\ No newline at end of file
+
+    /// The index of the material with the given name
+    pub fn material(&self, name: &str) -> Option<u8> {
+        self.materials.iter().position(|m| !m.name.is_empty() && m.name == name).map(|index| index as u8)
+    }
+
+    /// Add the material or replace the one with the same name, the default material 0 is
+    /// never replaced. Returns the index of the material.
+    pub fn define_material(&mut self, material: Material) -> Option<u8> {
+        let index = self.material(&material.name).or_else(|| {
+            self.materials.iter().skip(1).position(|m| m.name.is_empty()).map(|index| index as u8 + 1)
+        })?;
+        self.materials[index as usize] = material;
+        Some(index)
+    }
+}
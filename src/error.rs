@@ -0,0 +1,269 @@
+use crate::prelude::*;
+
+use std::fmt;
+
+/// The different kinds of errors reported by the interpreter
+#[derive(PartialEq, Debug, Clone)]
+pub enum ErrorKind {
+    /// An identifier which is neither a built-in word nor in the dictionary
+    UnknownIdentifier(String),
+    /// A command value without an implementation
+    UnknownCommand(String),
+    /// The stack was empty, holds the expected value and what it was expected for
+    StackUnderflow { expected: String, target: String },
+    /// The value on the stack is not valid for the word
+    WrongValue { expected: String, target: String },
+    /// The value on the stack has the wrong type
+    TypeMismatch { expected: String, found: String, target: String },
+    DivisionByZero,
+    /// An array without the closing bracket
+    MissingBracket,
+    /// A quotation without the closing brace
+    MissingBrace,
+    /// A string without the closing quote
+    UnterminatedString,
+    /// A hex color which is not "#rgb" or "#rrggbb"
+    InvalidColor(String),
+    /// A token which is not allowed at this position
+    UnexpectedToken(String),
+    /// A parsing word without its argument
+    MissingArgument(String),
+    InvalidDefinition,
+    UnterminatedDefinition,
+    /// A control flow word without its opening word, i.e. THEN without IF
+    UnmatchedControl { word: String, expected: String },
+    /// A control structure which is not closed in the word definition
+    UnterminatedControl(String),
+    /// A word which can only be used inside a word definition
+    CompileOnly(String),
+    /// A word which can not be used inside a word definition
+    InterpretOnly(String),
+    /// I or J outside of a DO loop
+    OutsideLoop(String),
+    RecursionLimit { limit: usize, word: String },
+    /// A file could not be read or written
+    Io { path: String, message: String },
+    /// All material slots are in use
+    MaterialLimit,
+}
+
+use ErrorKind::*;
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnknownIdentifier(name) => write!(f, "Unknown identifier: {}", name),
+            UnknownCommand(cmd) => write!(f, "Unknown command: {}", cmd),
+            StackUnderflow { expected, target } => write!(f, "Stack is empty. Expected {} for {}.", expected, target),
+            WrongValue { expected, target } => write!(f, "Wrong value on stack. Expected {} for {}.", expected, target),
+            TypeMismatch { expected, found, target } => write!(f, "Wrong type on stack. Expected {} for {}, found {}.", expected, target, found),
+            DivisionByZero => write!(f, "Division by zero."),
+            MissingBracket => write!(f, "Missing ']' after Array."),
+            MissingBrace => write!(f, "Missing '}}' after quotation."),
+            UnterminatedString => write!(f, "Unterminated string."),
+            InvalidColor(lexeme) => write!(f, "Invalid color {}, use #rgb or #rrggbb.", lexeme),
+            UnexpectedToken(lexeme) => write!(f, "Unexpected token: {}.", lexeme),
+            MissingArgument(word) => write!(f, "Missing argument after {}.", word),
+            InvalidDefinition => write!(f, "Invalid word definition."),
+            UnterminatedDefinition => write!(f, "Unterminated word definition."),
+            UnmatchedControl { word, expected } => write!(f, "\"{}\" without matching \"{}\".", word, expected),
+            UnterminatedControl(word) => write!(f, "Unterminated \"{}\" in word definition.", word),
+            CompileOnly(word) => write!(f, "\"{}\" is only allowed inside a word definition.", word),
+            InterpretOnly(word) => write!(f, "\"{}\" is not allowed inside a word definition.", word),
+            OutsideLoop(word) => write!(f, "\"{}\" used outside of a DO loop.", word),
+            RecursionLimit { limit, word } => write!(f, "Recursion limit of {} exceeded in \"{}\".", limit, word),
+            Io { path, message } => write!(f, "Could not access \"{}\": {}", path, message),
+            MaterialLimit => write!(f, "All {} materials are defined.", MATERIAL_COUNT - 1),
+        }
+    }
+}
+
+/// An error together with the position of the offending token in the source.
+/// Errors raised while executing are located at the top level token which caused them.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RpuError {
+    pub kind            : ErrorKind,
+
+    /// Line and column of the token, starting at 1. 0 if the error has no position (yet).
+    pub line            : usize,
+    pub column          : usize,
+    pub lexeme          : String,
+
+    /// The path and content of the included file the position refers to, None for the input itself
+    pub file            : Option<Box<(String, String)>>,
+}
+
+impl RpuError {
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            line        : 0,
+            column      : 0,
+            lexeme      : String::new(),
+            file        : None,
+        }
+    }
+
+    /// Create an error located at the given token
+    pub fn at(kind: ErrorKind, token: &Token) -> Self {
+        let mut error = Self::new(kind);
+        error.locate(token);
+        error
+    }
+
+    /// Shortcut for a StackUnderflow error
+    pub fn stack_underflow(expected: &str, target: &str) -> Self {
+        Self::new(StackUnderflow { expected: expected.into(), target: target.into() })
+    }
+
+    /// Shortcut for a WrongValue error
+    pub fn wrong_value(expected: &str, target: &str) -> Self {
+        Self::new(WrongValue { expected: expected.into(), target: target.into() })
+    }
+
+    /// Shortcut for a TypeMismatch error
+    pub fn type_mismatch(expected: &str, found: &Value, target: &str) -> Self {
+        Self::new(TypeMismatch { expected: expected.into(), found: found.type_name().into(), target: target.into() })
+    }
+
+    /// Set the position to the given token if the error is not located yet
+    pub fn locate(&mut self, token: &Token) {
+        if self.line == 0 {
+            self.line = token.line;
+            self.column = token.column;
+            self.lexeme = token.lexeme.clone();
+        }
+    }
+
+    /// Locate the error in the given included file, errors which are already located in a
+    /// nested file keep their file.
+    pub fn in_file(mut self, path: &str, source: &str) -> Self {
+        if self.line > 0 && self.file.is_none() {
+            self.file = Some(Box::new((path.to_string(), source.to_string())));
+        }
+        self
+    }
+
+    /// Render the error message and, if the error has a position, the offending source
+    /// line with the token underlined. Errors in included files show the line of that file.
+    pub fn render(&self, source: &str) -> Vec<String> {
+        let mut lines = vec![self.kind.to_string()];
+
+        let (file, source) = match self.file.as_deref() {
+            Some((path, source)) => (format!("{}, ", path), source.as_str()),
+            None => (String::new(), source),
+        };
+
+        if self.line > 0 {
+            if let Some(text) = source.lines().nth(self.line - 1) {
+                let number = self.line.to_string();
+                let pad = " ".repeat(number.len());
+
+                lines.push(format!("{}--> {}line {}, column {}", pad, file, self.line, self.column));
+                lines.push(format!("{} |", pad));
+                lines.push(format!("{} | {}", number, text));
+                lines.push(format!("{} | {}{}", pad, " ".repeat(self.column.saturating_sub(1)), "^".repeat(self.lexeme.chars().count().max(1))));
+            }
+        }
+
+        lines
+    }
+}
+
+impl fmt::Display for RpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (true, Some((path, _))) = (self.line > 0, self.file.as_deref()) {
+            write!(f, "{} ({}, line {}, column {})", self.kind, path, self.line, self.column)
+        } else
+        if self.line > 0 {
+            write!(f, "{} (line {}, column {})", self.kind, self.line, self.column)
+        } else {
+            write!(f, "{}", self.kind)
+        }
+    }
+}
+
+impl std::error::Error for RpuError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use super::ErrorKind::*;
+
+    use crate::test_util::evaluate;
+
+    /// The error of evaluating the input in a fresh interpreter
+    fn error(input: &str) -> RpuError {
+        evaluate(input).err().unwrap()
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(error("1 [ 2").kind, MissingBracket);
+        assert_eq!(error("{ 1").kind, MissingBrace);
+        assert_eq!(error("\"abc").kind, UnterminatedString);
+        assert_eq!(error("#zz").kind, InvalidColor("#ZZ".into()));
+        assert_eq!(error("1 ]").kind, UnexpectedToken("]".into()));
+        // Arrays and quotations have to nest
+        assert_eq!(error("{ [ } ] .S").kind, MissingBracket);
+        assert_eq!(error("[ { ] }").kind, UnexpectedToken("]".into()));
+        assert_eq!(error("INCLUDE").kind, MissingArgument("INCLUDE".into()));
+        assert_eq!(error("1 2 IF").kind, CompileOnly("IF".into()));
+        assert_eq!(error(": f 1 THEN ;").kind, UnmatchedControl { word: "THEN".into(), expected: "IF".into() });
+    }
+
+    #[test]
+    fn unexpected_tokens() {
+        let err = error("1 2 @ .S");
+        assert_eq!(err.kind, UnexpectedToken("@".into()));
+        assert_eq!((err.line, err.column), (1, 5));
+
+        // Keywords and punctuation the language does not use
+        for (input, lexeme) in [("1 and 2", "AND"), ("for", "FOR"), ("let", "LET"), ("( 1 )", "("), ("1 , 2", ","), ("! 1", "!")] {
+            assert_eq!(error(input).kind, UnexpectedToken(lexeme.into()), "{}", input);
+        }
+
+        // Comments are still skipped
+        assert!(evaluate("1 // a comment\n2 +").is_ok());
+    }
+
+    #[test]
+    fn runtime_errors() {
+        assert_eq!(error("unknown-word").kind, UnknownIdentifier("UNKNOWN-WORD".into()));
+        assert_eq!(error("1 0 /").kind, DivisionByZero);
+        assert_eq!(error(": open [ ; : close ] ; close").kind, UnmatchedControl { word: "]".into(), expected: "[".into() });
+        assert_eq!(error("DUP").kind, StackUnderflow { expected: "value".into(), target: "\"DUP\"".into() });
+        assert_eq!(error("\"a\" SPHERE").kind, TypeMismatch { expected: "number".into(), found: "string".into(), target: "\"radius\" of Sphere".into() });
+        assert!(matches!(error("LOAD missing-file.rpuw").kind, Io { .. }));
+    }
+
+    #[test]
+    fn errors_are_located_at_the_token() {
+        let err = error("1 2 +\n3 0 /");
+        assert_eq!((err.line, err.column, err.lexeme.as_str()), (2, 5, "/"));
+
+        let lines = err.render("1 2 +\n3 0 /");
+        assert_eq!(lines[0], "Division by zero.");
+        assert_eq!(lines.last().unwrap(), "  |     ^");
+
+        // Columns count characters, not bytes
+        let err = error("\"äöü\" 1 0 /");
+        assert_eq!(err.column, 11);
+        assert_eq!(err.render("\"äöü\" 1 0 /").last().unwrap(), "  |           ^");
+    }
+
+    #[test]
+    fn errors_of_included_files_keep_their_file() {
+        let path = std::env::temp_dir().join(format!("rpu-include-{}.rpu", std::process::id()));
+        std::fs::write(&path, "1 2 +\n\n1 #zz\n").unwrap();
+        let path = path.to_string_lossy().to_string();
+
+        let err = error(&format!("INCLUDE {}", path));
+        _ = std::fs::remove_file(&path);
+
+        assert_eq!(err.kind, InvalidColor("#ZZ".into()));
+        assert_eq!((err.line, err.column), (3, 3));
+        assert_eq!(err.file.as_deref().map(|(p, _)| p.as_str()), Some(path.as_str()));
+        assert!(err.render("").iter().any(|l| l == "3 | 1 #zz"));
+    }
+}
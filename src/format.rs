@@ -0,0 +1,441 @@
+//! The binary world file format.
+//!
+//! A file starts with the magic bytes "RPUW" and a u32 format version, followed by chunks.
+//! Every chunk is a four byte tag, the u32 length of its payload and the payload itself.
+//! All numbers are little endian. Readers skip chunks they do not know, so newer versions
+//! can add chunks without breaking older readers.
+//!
+//! Chunks of version 1:
+//!  - "CAMR": the camera, 19 f32 values
+//!  - "PALT": the name of the palette the colors refer to
+//!  - "MAPS": resolution (u32) and storage backend (u8) of the map
+//!  - "TILE": one per tile, key (3 x i32), size (u32), storage (u8), then the voxels in
+//!    index order as runs of count (u32), present (u8), color (u8), material (u8)
+//!  - "MATL": the defined materials as count (u32), then per material its index (u8), name,
+//!    albedo as present (u8) and color (u8), then roughness, metallic, reflectance, emission
+//!    and transparency (5 x f32)
+
+use crate::prelude::*;
+
+const MAGIC: &[u8; 4] = b"RPUW";
+
+/// The version written by this build
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Writes the binary representation
+pub(crate) struct Writer {
+    pub data            : Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self {
+            data        : vec![],
+        }
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn vec3(&mut self, v: Vec3f) {
+        self.f32(v.x);
+        self.f32(v.y);
+        self.f32(v.z);
+    }
+
+    pub fn string(&mut self, v: &str) {
+        self.u32(v.len() as u32);
+        self.data.extend_from_slice(v.as_bytes());
+    }
+
+    /// Append a chunk with the payload of the given writer
+    pub fn chunk(&mut self, tag: &[u8; 4], payload: Writer) {
+        self.data.extend_from_slice(tag);
+        self.u32(payload.data.len() as u32);
+        self.data.extend(payload.data);
+    }
+}
+
+/// Reads the binary representation, every read fails at the end of the data
+pub(crate) struct Reader<'a> {
+    data                : &'a [u8],
+    pos                 : usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos         : 0,
+        }
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(count).filter(|end| *end <= self.data.len()).ok_or("Unexpected end of file.")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// A size stored as an i32, negative sizes are invalid
+    pub fn size(&mut self) -> Result<usize, String> {
+        usize::try_from(self.i32()?).map_err(|_| "Invalid size.".to_string())
+    }
+
+    pub fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn vec3(&mut self) -> Result<Vec3f, String> {
+        Ok(vec3f(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| "Invalid string.".to_string())
+    }
+}
+
+fn storage_to_u8(storage: StorageKind) -> u8 {
+    match storage {
+        StorageKind::Dense => 0,
+        StorageKind::Bricks => 1,
+    }
+}
+
+fn storage_from_u8(v: u8) -> StorageKind {
+    if v == 0 { StorageKind::Dense } else { StorageKind::Bricks }
+}
+
+impl World {
+
+    /// Write the world to the given file
+    pub fn save(&self, path: &str) -> Result<(), RpuError> {
+        std::fs::write(path, self.to_bytes()).map_err(|err| RpuError::new(ErrorKind::Io { path: path.into(), message: err.to_string() }))
+    }
+
+    /// Read a world from the given file
+    pub fn load(path: &str) -> Result<World, RpuError> {
+        let data = std::fs::read(path).map_err(|err| RpuError::new(ErrorKind::Io { path: path.into(), message: err.to_string() }))?;
+        World::from_bytes(&data).map_err(|message| RpuError::new(ErrorKind::Io { path: path.into(), message }))
+    }
+
+    /// The binary representation of the world
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.data.extend_from_slice(MAGIC);
+        w.u32(FORMAT_VERSION);
+
+        let c = &self.camera;
+        let mut camera = Writer::new();
+        camera.vec3(c.origin);
+        camera.vec3(c.center);
+        camera.f32(c.fov);
+        camera.f32(c.distance);
+        camera.vec3(c.forward);
+        camera.vec3(c.up);
+        camera.vec3(c.right);
+        camera.f32(c.orbit_x);
+        camera.f32(c.orbit_y);
+        w.chunk(b"CAMR", camera);
+
+        let mut palette = Writer::new();
+        palette.string(&self.palette);
+        w.chunk(b"PALT", palette);
+
+        let mut map = Writer::new();
+        map.u32(self.map.resolution as u32);
+        map.u8(storage_to_u8(self.map.storage));
+        w.chunk(b"MAPS", map);
+
+        // Only materials defined by MATERIAL are saved, the others keep their defaults
+        let defined : Vec<(usize, &Material)> = self.materials.iter().enumerate().filter(|(_, m)| !m.name.is_empty()).collect();
+        if !defined.is_empty() {
+            let mut materials = Writer::new();
+            materials.u32(defined.len() as u32);
+            for (index, m) in defined {
+                materials.u8(index as u8);
+                materials.string(&m.name);
+                materials.u8(m.albedo.is_some() as u8);
+                materials.u8(m.albedo.unwrap_or(0));
+                materials.f32(m.roughness);
+                materials.f32(m.metallic);
+                materials.f32(m.reflectance);
+                materials.f32(m.emission);
+                materials.f32(m.transparency);
+            }
+            w.chunk(b"MATL", materials);
+        }
+
+        let mut keys : Vec<&(i32, i32, i32)> = self.map.tiles.keys().collect();
+        keys.sort();
+
+        for key in keys {
+            let tile = &self.map.tiles[key];
+            let mut t = Writer::new();
+            t.i32(key.0);
+            t.i32(key.1);
+            t.i32(key.2);
+            t.u32(tile.size as u32);
+            t.u8(storage_to_u8(tile.storage()));
+
+            // Run length encode the voxels
+            let mut run : Option<(Option<(u8, u8)>, u32)> = None;
+            let write_run = |t: &mut Writer, voxel: Option<(u8, u8)>, count: u32| {
+                t.u32(count);
+                if let Some((color, material)) = voxel {
+                    t.u8(1);
+                    t.u8(color);
+                    t.u8(material);
+                } else {
+                    t.u8(0);
+                    t.u8(0);
+                    t.u8(0);
+                }
+            };
+
+            for z in 0..tile.size {
+                for y in 0..tile.size {
+                    for x in 0..tile.size {
+                        let voxel = tile.get_voxel(x, y, z);
+                        match &mut run {
+                            Some((value, count)) if *value == voxel => *count += 1,
+                            _ => {
+                                if let Some((value, count)) = run {
+                                    write_run(&mut t, value, count);
+                                }
+                                run = Some((voxel, 1));
+                            }
+                        }
+                    }
+                }
+            }
+            if let Some((value, count)) = run {
+                write_run(&mut t, value, count);
+            }
+
+            w.chunk(b"TILE", t);
+        }
+
+        w.data
+    }
+
+    /// Create a world out of its binary representation
+    pub fn from_bytes(data: &[u8]) -> Result<World, String> {
+        let mut r = Reader::new(data);
+
+        if r.bytes(4).ok() != Some(&MAGIC[..]) {
+            return Err("Not an RPU world file.".into());
+        }
+
+        let version = r.u32()?;
+        if version == 0 {
+            return Err(format!("Unsupported file version {}.", version));
+        }
+
+        let mut world = World::new();
+
+        while !r.is_at_end() {
+            let tag = r.bytes(4)?;
+            let length = r.u32()? as usize;
+            let mut c = Reader::new(r.bytes(length)?);
+
+            match tag {
+                b"CAMR" => {
+                    let camera = &mut world.camera;
+                    camera.origin = c.vec3()?;
+                    camera.center = c.vec3()?;
+                    camera.fov = c.f32()?;
+                    camera.distance = c.f32()?;
+                    camera.forward = c.vec3()?;
+                    camera.up = c.vec3()?;
+                    camera.right = c.vec3()?;
+                    camera.orbit_x = c.f32()?;
+                    camera.orbit_y = c.f32()?;
+                },
+                b"PALT" => {
+                    world.palette = c.string()?;
+                },
+                b"MAPS" => {
+                    let resolution = c.u32()? as usize;
+                    if !(1..=256).contains(&resolution) {
+                        return Err(format!("Invalid map resolution {}.", resolution));
+                    }
+                    world.map.set_resolution(resolution);
+                    world.map.storage = storage_from_u8(c.u8()?);
+                },
+                b"MATL" => {
+                    world.materials = vec![Material::new(); MATERIAL_COUNT];
+                    for _ in 0..c.u32()? {
+                        let index = c.u8()? as usize;
+                        if index == 0 || index >= MATERIAL_COUNT {
+                            return Err(format!("Invalid material index {}.", index));
+                        }
+                        let m = &mut world.materials[index];
+                        m.name = c.string()?;
+                        let albedo = (c.u8()?, c.u8()?);
+                        m.albedo = if albedo.0 != 0 { Some(albedo.1) } else { None };
+                        m.roughness = c.f32()?;
+                        m.metallic = c.f32()?;
+                        m.reflectance = c.f32()?;
+                        m.emission = c.f32()?;
+                        m.transparency = c.f32()?;
+                    }
+                },
+                b"TILE" => {
+                    let key = (c.i32()?, c.i32()?, c.i32()?);
+                    let size = c.u32()? as usize;
+                    if size != world.map.resolution {
+                        return Err(format!("Invalid tile size {}.", size));
+                    }
+                    let mut tile = Tile::with_storage(size, storage_from_u8(c.u8()?));
+
+                    let total = size * size * size;
+                    let mut index = 0;
+                    while index < total {
+                        let count = c.u32()? as usize;
+                        let present = c.u8()?;
+                        let color = c.u8()?;
+                        let material = c.u8()?;
+
+                        if count == 0 || index + count > total {
+                            return Err("Invalid voxel data.".into());
+                        }
+
+                        if present != 0 {
+                            for i in index..index + count {
+                                tile.set_voxel(i % size, (i / size) % size, i / (size * size), Some((color, material)));
+                            }
+                        }
+                        index += count;
+                    }
+
+                    tile.compress();
+                    world.set_tile(vec3i(key.0, key.1, key.2), tile);
+                },
+                _ => {
+                    // Chunk of a newer version
+                }
+            }
+        }
+
+        world.map.build_aabb();
+
+        Ok(world)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn world_round_trip() {
+        let mut world = baked(TWO_MATERIALS).preview;
+        world.camera.origin = vec3f(1.0, 2.0, 3.0);
+
+        let loaded = World::from_bytes(&world.to_bytes()).unwrap();
+
+        assert!(!tile_voxels(&world).is_empty());
+        assert_eq!(tile_voxels(&loaded), tile_voxels(&world));
+        assert!(tile_voxels(&loaded).iter().any(|v| v.4 == (40, 1)));
+        assert_eq!(loaded.camera, world.camera);
+        assert_eq!(loaded.palette, world.palette);
+        assert_eq!(loaded.map.resolution, world.map.resolution);
+        assert_eq!(loaded.map.storage, world.map.storage);
+
+        // The defined materials, the others are the default material
+        assert_eq!(loaded.materials, world.materials);
+        assert_eq!(loaded.materials[1].name, "GOLD");
+    }
+
+    #[test]
+    fn load_restores_the_materials() {
+        let path = std::env::temp_dir().join(format!("rpu-materials-{}.rpuw", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+
+        let saved = baked(&format!("{} SAVE {}", TWO_MATERIALS, path));
+        let loaded = baked(&format!("MATERIAL stone 1 0 0 ; MATERIAL gold 0.5 0 0 ; LOAD {}", path));
+        _ = std::fs::remove_file(&path);
+
+        // The voxels refer to gold at its saved index, its other definition is dropped
+        assert_eq!(loaded.context.material("GOLD"), Some(1));
+        assert_eq!(loaded.context.materials[1], saved.context.materials[1]);
+        assert!(loaded.context.materials[2].name.is_empty());
+        assert_eq!(loaded.context.material("STONE"), None);
+    }
+
+    #[test]
+    fn invalid_world_data() {
+        let data = baked("0.3 SPHERE BAKE").preview.to_bytes();
+
+        assert!(World::from_bytes(b"NOPE").is_err());
+        assert!(World::from_bytes(&data[..data.len() - 3]).is_err());
+
+        // Chunks with a length past the end of the data
+        let mut broken = data[..8].to_vec();
+        broken.extend_from_slice(b"TILE");
+        broken.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(World::from_bytes(&broken).is_err());
+
+        // Map resolutions outside of 1..=256
+        for resolution in [0u32, 257] {
+            let mut broken = data[..8].to_vec();
+            broken.extend_from_slice(b"MAPS");
+            broken.extend_from_slice(&5u32.to_le_bytes());
+            broken.extend_from_slice(&resolution.to_le_bytes());
+            broken.push(0);
+            assert!(World::from_bytes(&broken).is_err());
+        }
+
+        // Materials can not replace the default material
+        let mut broken = data[..8].to_vec();
+        broken.extend_from_slice(b"MATL");
+        broken.extend_from_slice(&5u32.to_le_bytes());
+        broken.extend_from_slice(&1u32.to_le_bytes());
+        broken.push(0);
+        assert!(World::from_bytes(&broken).is_err());
+
+        // Tiles which do not match the map resolution
+        let resolution = World::from_bytes(&data).unwrap().map.resolution as u32;
+        let mut broken = data[..8].to_vec();
+        broken.extend_from_slice(b"TILE");
+        broken.extend_from_slice(&(12u32 + 4 + 1 + 7).to_le_bytes());
+        broken.extend_from_slice(&[0; 12]);
+        broken.extend_from_slice(&(resolution + 1).to_le_bytes());
+        broken.push(0);
+        broken.extend_from_slice(&(resolution + 1).pow(3).to_le_bytes());
+        broken.extend_from_slice(&[0, 0, 0]);
+        assert!(World::from_bytes(&broken).is_err());
+    }
+}
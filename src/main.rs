@@ -10,6 +10,15 @@ pub mod palette;
 pub mod scanner;
 pub mod value;
 pub mod sdf3d;
+pub mod error;
+pub mod format;
+pub mod vox;
+pub mod mesh;
+pub mod surface;
+pub mod material;
+
+#[cfg(test)]
+mod test_util;
 
 use rust_embed::RustEmbed;
 #[derive(RustEmbed)]
@@ -23,7 +32,7 @@ pub mod prelude {
     pub use rand::{thread_rng, Rng, rngs::ThreadRng};
     pub use serde::{Deserialize, Serialize};
     pub use maths_rs::prelude::*;
-    pub use rustc_hash::FxHashMap;
+    pub use rustc_hash::{FxHashMap, FxHashSet};
     pub use fontdue::Font;
     pub use crate::Embedded;
 
@@ -32,13 +41,17 @@ pub mod prelude {
     pub use crate::camera::Camera;
     pub use crate::misc::*;
     pub use crate::map::Map;
-    pub use crate::tile::Tile;
+    pub use crate::tile::{Tile, StorageKind};
     pub use crate::world::World;
     pub use crate::context::Context;
-    pub use crate::palette::Palette;
+    pub use crate::palette::{Palette, ColorMetric, Dither, named_color, parse_hex_color};
     pub use crate::scanner::{Scanner, Token, TokenType};
     pub use crate::value::Value;
     pub use crate::sdf3d::*;
+    pub use crate::error::{RpuError, ErrorKind};
+    pub use crate::mesh::Mesh;
+    pub use crate::surface::TriangleMesh;
+    pub use crate::material::{Material, MATERIAL_COUNT};
 }
 
 use prelude::*;
@@ -51,16 +64,140 @@ use std::io::Write
 
 use viuer::Config;
 
+/// Command line options
+struct Options {
+    script          : Option<String>,
+    output          : String,
+
+    width           : usize,
+    height          : usize,
+    samples         : i32,
+
+    preview         : bool,
+    quiet           : bool,
+}
+
+const USAGE: &str = "Usage: rpu [script.rpu] [options]
+
+Options:
+  -o, --output <file>     Write the rendered image to <file> (default out.png)
+  -w, --width <pixels>    Width of the rendered image (default 1200)
+  -H, --height <pixels>   Height of the rendered image (default 800)
+  -s, --samples <count>   Number of accumulated render passes (default 10)
+      --no-preview        Do not print the rendered image to the terminal
+  -q, --quiet             Only print errors
+  -h, --help              Print this help";
+
+/// Parse the command line arguments
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        script      : None,
+        output      : "out.png".to_string(),
+
+        width       : 1200,
+        height      : 800,
+        samples     : 10,
+
+        preview     : true,
+        quiet       : false,
+    };
+
+    fn number<T: std::str::FromStr>(arg: &str, value: Option<String>) -> Result<T, String> {
+        if let Some(value) = value {
+            value.parse::<T>().map_err(|_| format!("Invalid value \"{}\" for {}.", value, arg))
+        } else {
+            Err(format!("Missing value after {}.", arg))
+        }
+    }
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                options.output = args.next().ok_or(format!("Missing file name after {}.", arg))?;
+            },
+            "-w" | "--width" => {
+                options.width = number(&arg, args.next())?;
+            },
+            "-H" | "--height" => {
+                options.height = number(&arg, args.next())?;
+            },
+            "-s" | "--samples" => {
+                options.samples = number(&arg, args.next())?;
+            },
+            "--no-preview" => {
+                options.preview = false;
+            },
+            "-q" | "--quiet" => {
+                options.quiet = true;
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            },
+            _ => {
+                if arg.starts_with('-') {
+                    return Err(format!("Unknown option: {}", arg));
+                }
+                options.script = Some(arg);
+            }
+        }
+    }
+
+    if options.width == 0 || options.height == 0 || options.samples < 1 {
+        return Err("Width, height and samples must be at least 1.".to_string());
+    }
+
+    Ok(options)
+}
+
 fn main() {
 
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    // Scripts run with an empty dictionary and do not change ./dictionary.json
     let mut rpu = RPU::new();
-    rpu.reload();
+    rpu.context.samples = options.samples;
+
+    let mut buffer = ColorBuffer::new(options.width, options.height);
+
+    // Run a script file non-interactively
+
+    if let Some(script) = &options.script {
+        let rc = rpu.process_file(script, &mut buffer);
+
+        if rc.0 {
+            write_buffer(&buffer, Some(&options.output), false);
+        }
+
+        if rpu.last_error.is_some() {
+            for l in rc.1 {
+                eprintln!("{}", l);
+            }
+            std::process::exit(1);
+        }
 
-    let mut buffer = ColorBuffer::new(1200, 800);
+        if !options.quiet {
+            for l in rc.1 {
+                println!("{}", l);
+            }
+        }
+        return;
+    }
 
-    println!("Welcome to the RPU Language Interpreter.");
-    print!("rpu % ");
-    io::stdout().flush().unwrap();
+    rpu.reload();
+
+    if !options.quiet {
+        println!("Welcome to the RPU Language Interpreter.");
+        print!("rpu % ");
+        io::stdout().flush().unwrap();
+    }
 
     for line in io::stdin().lines() {
         if let Some(line) = line.ok() {
@@ -70,16 +207,24 @@ fn main() {
                 let rc = rpu.process(line.trim().into(), &mut buffer);
 
                 if rc.0 {
-                    write_buffer(&buffer, Some("out.png"), true);
+                    write_buffer(&buffer, Some(&options.output), options.preview && !options.quiet);
                 }
 
-                for l in rc.1 {
-                    println!("{}", l);
+                if !options.quiet || rpu.last_error.is_some() {
+                    for l in rc.1 {
+                        println!("{}", l);
+                    }
                 }
             }
 
-            print!("rpu % ");
-            io::stdout().flush().unwrap();
+            if !options.quiet {
+                if rpu.is_incomplete() {
+                    print!("...   ");
+                } else {
+                    print!("rpu % ");
+                }
+                io::stdout().flush().unwrap();
+            }
         } else {
             break;
         }
@@ -93,21 +238,26 @@ fn write_buffer(buffer: &ColorBuffer, file_name: Option<&str>, terminal: bool) {
     let data = buffer.to_u8_vec();
 
     if let Some(path) = file_name {
-        if let Some(file) = File::create(path).ok() {
-            let w = BufWriter::new(file);
-            let mut encoder = png::Encoder::new(w, buffer.width as u32, buffer.height as u32);
-            encoder.set_color(png::ColorType::Rgba);
-            encoder.set_depth(png::BitDepth::Eight);
-            // Adding text chunks to the header
-            encoder
-                .add_text_chunk(
-                    "RPU".to_string(),
-                    "This image was procedurally generated by rpu-lang.org".to_string(),
-                )
-                .unwrap();
-
-            if let Some(mut writer) = encoder.write_header().ok() {
-                writer.write_image_data(&data).unwrap();
+        match File::create(path) {
+            Ok(file) => {
+                let w = BufWriter::new(file);
+                let mut encoder = png::Encoder::new(w, buffer.width as u32, buffer.height as u32);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                // Adding text chunks to the header
+                encoder
+                    .add_text_chunk(
+                        "RPU".to_string(),
+                        "This image was procedurally generated by rpu-lang.org".to_string(),
+                    )
+                    .unwrap();
+
+                if let Some(mut writer) = encoder.write_header().ok() {
+                    writer.write_image_data(&data).unwrap();
+                }
+            },
+            Err(err) => {
+                eprintln!("Could not write \"{}\": {}", path, err);
             }
         }
     }
@@ -128,6 +278,8 @@ fn write_buffer(buffer: &ColorBuffer, file_name: Option<&str>, terminal: bool) {
             d.copy_from_slice(&data[..]);
         }
 
-        viuer::print(&img, &conf).expect("Terminal output failed.");
+        if let Err(err) = viuer::print(&img, &conf) {
+            eprintln!("Terminal output failed: {}", err);
+        }
     }
 }
\ No newline at end of file
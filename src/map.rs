@@ -6,12 +6,20 @@ pub struct Map {
     #[serde(skip)]
     pub tiles               : FxHashMap<(i32, i32, i32), Tile>,
     pub aabb                : Option<AABB>,
+
+    /// The storage backend of new tiles
+    #[serde(default)]
+    pub storage             : StorageKind,
+
+    /// The number of voxels along each edge of a tile
+    #[serde(default = "Map::default_resolution")]
+    pub resolution          : usize,
 }
 
 impl Map {
     pub fn new() -> Self {
 
-        let mut tiles  = FxHashMap::default();
+        let tiles  = FxHashMap::default();
 
         // let mut tile = Tile::new(Map::tile_size());
         // tile.build_aabb();
@@ -22,7 +30,11 @@ impl Map {
 
         Self {
             tiles,
-            aabb            : None
+            aabb            : None,
+
+            storage         : StorageKind::default(),
+
+            resolution      : Map::default_resolution(),
         }
     }
 
@@ -38,7 +50,7 @@ impl Map {
             for y in (bbox.min.y.floor() as i32)..(bbox.max.y.ceil() as i32) {
                 for z in (bbox.min.z.floor() as i32)..(bbox.max.z.ceil() as i32) {
                     if self.tiles.contains_key(&(x, y, z)) == false {
-                        self.tiles.insert((x, y, z), Tile::new(Map::tile_size()));
+                        self.tiles.insert((x, y, z), Tile::with_storage(self.resolution, self.storage));
                     }
 
                     tiles.push(Vec3i::new(x, y, z));
@@ -91,6 +103,11 @@ impl Map {
         //println!("{:?}", self.aabb);
     }
 
+    /// The memory used by the voxels of all tiles in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.tiles.values().map(|t| t.memory_usage()).sum()
+    }
+
     /// Ray AABB intersection. Taken from https://github.com/svenstaro/bvh/blob/master/src/ray.rs
     pub fn ray_aabb(&self, ray: &Ray, aabb: &AABB) -> bool {
         let mut ray_min = (aabb[ray.sign_x].x - ray.o.x) * ray.inv_direction.x;
@@ -111,8 +128,85 @@ impl Map {
         max(ray_min, 0.0) <= ray_max
     }
 
-    /// The project tile size
-    pub fn tile_size() -> usize {
+    /// The tile size of the map
+    pub fn tile_size(&self) -> usize {
+        self.resolution
+    }
+
+    /// The resolution of new maps
+    pub fn default_resolution() -> usize {
         50
     }
-}
\ No newline at end of file
+
+    /// Set the resolution, existing tiles are resampled
+    pub fn set_resolution(&mut self, resolution: usize) {
+        if resolution == self.resolution {
+            return;
+        }
+
+        for tile in self.tiles.values_mut() {
+            tile.resize(resolution);
+            tile.compress();
+            tile.build_aabb();
+        }
+
+        self.resolution = resolution;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::{baked, evaluate, tile_voxels};
+
+    #[test]
+    fn resolution_resamples_tiles() {
+        for storage in [StorageKind::Dense, StorageKind::Bricks] {
+            let mut map = Map::new();
+            map.storage = storage;
+            map.resolution = 4;
+            map.create_tiles_aabb(&AABB { min: Vec3f::zero(), max: vec3f(1.0, 1.0, 1.0) });
+            map.tiles.get_mut(&(0, 0, 0)).unwrap().set_voxel(1, 2, 3, Some((5, 1)));
+
+            // Each voxel becomes a 2x2x2 block at twice the resolution
+            map.set_resolution(8);
+            let tile = &map.tiles[&(0, 0, 0)];
+            assert_eq!((tile.size, tile.storage()), (8, storage));
+            for z in 0..8 {
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let inside = x / 2 == 1 && y / 2 == 2 && z / 2 == 3;
+                        assert_eq!(tile.get_voxel(x, y, z), if inside { Some((5, 1)) } else { None });
+                    }
+                }
+            }
+
+            // And back to the original voxel
+            map.set_resolution(4);
+            let tile = &map.tiles[&(0, 0, 0)];
+            assert_eq!(tile.size, 4);
+            assert_eq!((0..64).filter(|i| tile.get_voxel(i % 4, i / 4 % 4, i / 16).is_some()).count(), 1);
+            assert_eq!(tile.get_voxel(1, 2, 3), Some((5, 1)));
+
+            // New tiles use the new resolution
+            map.create_tiles_aabb(&AABB { min: vec3f(1.0, 0.0, 0.0), max: vec3f(2.0, 1.0, 1.0) });
+            assert_eq!(map.tiles[&(1, 0, 0)].size, 4);
+        }
+    }
+
+    #[test]
+    fn resolution_word() {
+        let rpu = baked("0.3 SPHERE BAKE 25 RESOLUTION");
+        assert!(rpu.preview.map.tiles.values().all(|tile| tile.size == 25));
+        assert!(!tile_voxels(&rpu.preview).is_empty());
+
+        let coarse = baked("10 RESOLUTION 0.3 SPHERE BAKE");
+        let fine = baked("0.3 SPHERE BAKE");
+        assert!(tile_voxels(&coarse.preview).len() < tile_voxels(&fine.preview).len());
+
+        for input in ["0 RESOLUTION", "257 RESOLUTION"] {
+            let kind = evaluate(input).err().map(|err| err.kind);
+            assert!(matches!(kind, Some(ErrorKind::WrongValue { .. })), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::prelude::*;
+
+/// The number of materials, the material of a voxel is a byte
+pub const MATERIAL_COUNT: usize = 256;
+
+/// The surface properties used by the path tracer
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Material {
+    /// Empty for materials which were not defined by MATERIAL
+    pub name            : String,
+    /// Replaces the color of the shape when baked
+    pub albedo          : Option<u8>,
+
+    pub roughness       : f32,
+    pub metallic        : f32,
+    /// The probability of a specular bounce
+    pub reflectance     : f32,
+    /// Emitted light, multiplied with the voxel color
+    pub emission        : f32,
+    /// The probability of a ray passing through the voxel
+    pub transparency    : f32,
+}
+
+impl Material {
+    pub fn new() -> Self {
+        Self {
+            name        : String::new(),
+            albedo      : None,
+
+            roughness   : 1.0,
+            metallic    : 0.0,
+            reflectance : 0.0,
+            emission    : 0.0,
+            transparency: 0.0,
+        }
+    }
+
+    /// A named material, smooth and metallic materials reflect more of the light specularly
+    pub fn named(name: &str, roughness: f32, metallic: f32, emission: f32) -> Self {
+        Self {
+            name        : name.to_string(),
+            roughness,
+            metallic,
+            reflectance : metallic + (1.0 - metallic) * (1.0 - roughness) * 0.5,
+            emission,
+            ..Self::new()
+        }
+    }
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,469 @@
+use crate::prelude::*;
+use crate::format::Writer;
+
+use std::fmt::Write as _;
+
+/// A quad mesh in world coordinates, every quad has a single palette color
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Mesh {
+    /// Four corners per quad, counter clockwise seen from the front
+    pub vertices            : Vec<Vec3f>,
+    /// One normal and color per quad
+    pub normals             : Vec<Vec3f>,
+    pub colors              : Vec<u8>,
+}
+
+impl Mesh {
+    pub fn new() -> Self {
+        Self {
+            vertices        : vec![],
+            normals         : vec![],
+            colors          : vec![],
+        }
+    }
+
+    /// The number of quads
+    pub fn quads(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// Triangle indices of the quads
+    fn indices(&self) -> Vec<u32> {
+        let mut indices = vec![];
+        for quad in 0..self.quads() as u32 {
+            let i = quad * 4;
+            indices.extend_from_slice(&[i, i + 1, i + 2, i, i + 2, i + 3]);
+        }
+        indices
+    }
+
+    /// Write the mesh to the given file, the format is taken from the extension:
+    /// .obj (with a .mtl file next to it), .ply or .glb
+    pub fn write(&self, path: &str, palette: &Palette) -> Result<(), RpuError> {
+        let io_error = |message: String| RpuError::new(ErrorKind::Io { path: path.into(), message });
+        let lower = path.to_lowercase();
+
+        if lower.ends_with(".obj") {
+            let mtl_path = format!("{}.mtl", &path[..path.len() - 4]);
+            let mtl_name = std::path::Path::new(&mtl_path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            let (obj, mtl) = self.to_obj(&mtl_name, palette);
+            std::fs::write(&mtl_path, mtl).map_err(|err| RpuError::new(ErrorKind::Io { path: mtl_path.clone(), message: err.to_string() }))?;
+            std::fs::write(path, obj).map_err(|err| io_error(err.to_string()))
+        } else
+        if lower.ends_with(".ply") {
+            std::fs::write(path, self.to_ply(palette)).map_err(|err| io_error(err.to_string()))
+        } else
+        if lower.ends_with(".glb") {
+            std::fs::write(path, self.to_glb(palette)).map_err(|err| io_error(err.to_string()))
+        } else {
+            Err(io_error("Unsupported mesh format, use .obj, .ply or .glb.".into()))
+        }
+    }
+
+    /// Wavefront OBJ with one material per palette color
+    pub fn to_obj(&self, mtl_name: &str, palette: &Palette) -> (String, String) {
+        let mut obj = String::new();
+        let mut mtl = String::new();
+
+        _ = writeln!(obj, "# Generated by rpu-lang.org");
+        _ = writeln!(obj, "mtllib {}", mtl_name);
+
+        for v in &self.vertices {
+            _ = writeln!(obj, "v {} {} {}", v.x, v.y, v.z);
+        }
+        for n in &self.normals {
+            _ = writeln!(obj, "vn {} {} {}", n.x, n.y, n.z);
+        }
+
+        let mut used : Vec<u8> = self.colors.clone();
+        used.sort();
+        used.dedup();
+
+        for color in &used {
+            let c = palette_color(palette, *color);
+            _ = writeln!(mtl, "newmtl color_{}", color);
+            _ = writeln!(mtl, "Kd {} {} {}\n", c[0], c[1], c[2]);
+
+            _ = writeln!(obj, "usemtl color_{}", color);
+            for quad in 0..self.quads() {
+                if self.colors[quad] == *color {
+                    let i = quad * 4 + 1;
+                    let n = quad + 1;
+                    _ = writeln!(obj, "f {}//{} {}//{} {}//{} {}//{}", i, n, i + 1, n, i + 2, n, i + 3, n);
+                }
+            }
+        }
+
+        (obj, mtl)
+    }
+
+    /// ASCII PLY with vertex colors
+    pub fn to_ply(&self, palette: &Palette) -> String {
+        let mut ply = String::new();
+
+        _ = writeln!(ply, "ply\nformat ascii 1.0\ncomment Generated by rpu-lang.org");
+        _ = writeln!(ply, "element vertex {}", self.vertices.len());
+        _ = writeln!(ply, "property float x\nproperty float y\nproperty float z");
+        _ = writeln!(ply, "property float nx\nproperty float ny\nproperty float nz");
+        _ = writeln!(ply, "property uchar red\nproperty uchar green\nproperty uchar blue");
+        _ = writeln!(ply, "element face {}", self.quads());
+        _ = writeln!(ply, "property list uchar int vertex_indices\nend_header");
+
+        for (index, v) in self.vertices.iter().enumerate() {
+            let n = self.normals[index / 4];
+            let c = palette.colors.get(self.colors[index / 4] as usize).cloned().unwrap_or([0, 0, 0, 255]);
+            _ = writeln!(ply, "{} {} {} {} {} {} {} {} {}", v.x, v.y, v.z, n.x, n.y, n.z, c[0], c[1], c[2]);
+        }
+        for quad in 0..self.quads() {
+            let i = quad * 4;
+            _ = writeln!(ply, "4 {} {} {} {}", i, i + 1, i + 2, i + 3);
+        }
+
+        ply
+    }
+
+    /// Binary glTF 2.0 with positions, normals and linear vertex colors
+    pub fn to_glb(&self, palette: &Palette) -> Vec<u8> {
+        let indices = self.indices();
+
+        let mut bin = Writer::new();
+        let mut min = Vec3f::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vec3f::new(f32::MIN, f32::MIN, f32::MIN);
+
+        for v in &self.vertices {
+            bin.vec3(*v);
+            min = vec3f(min.x.min(v.x), min.y.min(v.y), min.z.min(v.z));
+            max = vec3f(max.x.max(v.x), max.y.max(v.y), max.z.max(v.z));
+        }
+        let normals_offset = bin.data.len();
+        for index in 0..self.vertices.len() {
+            bin.vec3(self.normals[index / 4]);
+        }
+        let colors_offset = bin.data.len();
+        for index in 0..self.vertices.len() {
+            let c = palette_color(palette, self.colors[index / 4]);
+            bin.vec3(vec3f(c[0].powf(2.2), c[1].powf(2.2), c[2].powf(2.2)));
+        }
+        let indices_offset = bin.data.len();
+        for i in &indices {
+            bin.u32(*i);
+        }
+        let length = bin.data.len();
+
+        let vertices = self.vertices.len();
+        let json = serde_json::json!({
+            "asset": { "version": "2.0", "generator": "rpu-lang.org" },
+            "scene": 0,
+            "scenes": [ { "nodes": [0] } ],
+            "nodes": [ { "mesh": 0 } ],
+            "meshes": [ { "primitives": [ {
+                "attributes": { "POSITION": 0, "NORMAL": 1, "COLOR_0": 2 },
+                "indices": 3,
+                "material": 0,
+            } ] } ],
+            "materials": [ { "pbrMetallicRoughness": { "baseColorFactor": [1.0, 1.0, 1.0, 1.0], "metallicFactor": 0.0, "roughnessFactor": 1.0 } } ],
+            "buffers": [ { "byteLength": length } ],
+            "bufferViews": [
+                { "buffer": 0, "byteOffset": 0, "byteLength": normals_offset, "target": 34962 },
+                { "buffer": 0, "byteOffset": normals_offset, "byteLength": colors_offset - normals_offset, "target": 34962 },
+                { "buffer": 0, "byteOffset": colors_offset, "byteLength": indices_offset - colors_offset, "target": 34962 },
+                { "buffer": 0, "byteOffset": indices_offset, "byteLength": length - indices_offset, "target": 34963 },
+            ],
+            "accessors": [
+                { "bufferView": 0, "componentType": 5126, "count": vertices, "type": "VEC3", "min": [min.x, min.y, min.z], "max": [max.x, max.y, max.z] },
+                { "bufferView": 1, "componentType": 5126, "count": vertices, "type": "VEC3" },
+                { "bufferView": 2, "componentType": 5126, "count": vertices, "type": "VEC3" },
+                { "bufferView": 3, "componentType": 5125, "count": indices.len(), "type": "SCALAR" },
+            ],
+        });
+
+        // Both chunks are padded to four bytes, JSON with spaces, binary with zeros
+        let mut json = json.to_string().into_bytes();
+        while !json.len().is_multiple_of(4) {
+            json.push(b' ');
+        }
+        while !bin.data.len().is_multiple_of(4) {
+            bin.data.push(0);
+        }
+
+        let mut glb = Writer::new();
+        glb.data.extend_from_slice(b"glTF");
+        glb.u32(2);
+        glb.u32((12 + 8 + json.len() + 8 + bin.data.len()) as u32);
+        glb.u32(json.len() as u32);
+        glb.data.extend_from_slice(b"JSON");
+        glb.data.extend(json);
+        glb.u32(bin.data.len() as u32);
+        glb.data.extend_from_slice(b"BIN\0");
+        glb.data.extend(bin.data);
+
+        glb.data
+    }
+}
+
+/// The sRGB color of the palette entry in 0..1
+fn palette_color(palette: &Palette, index: u8) -> [f32; 4] {
+    palette.colors_f.get(index as usize).cloned().unwrap_or([0.0, 0.0, 0.0, 1.0])
+}
+
+impl World {
+
+    /// Create a mesh of the map. Every exposed voxel face becomes part of a quad,
+    /// coplanar faces of the same color are merged greedily within each tile.
+    pub fn greedy_mesh(&self) -> Mesh {
+        let mut mesh = Mesh::new();
+
+        let res = self.map.resolution;
+        let dims = [res; 3];
+
+        let mut keys : Vec<&(i32, i32, i32)> = self.map.tiles.keys().collect();
+        keys.sort();
+
+        // The voxels of one tile at a time, 0 is empty, otherwise color + 1
+        let mut grid = vec![0_u16; res * res * res];
+
+        for key in keys {
+            let tile = &self.map.tiles[key];
+            if tile.is_empty() {
+                continue;
+            }
+
+            grid.fill(0);
+            for z in 0..tile.size {
+                for y in 0..tile.size {
+                    for x in 0..tile.size {
+                        if let Some((color, _)) = tile.get_voxel(x, y, z) {
+                            // Tiles which were resized keep their own size, scale them to the map resolution
+                            let (x, y, z) = (x * res / tile.size, y * res / tile.size, z * res / tile.size);
+                            grid[x + y * res + z * res * res] = color as u16 + 1;
+                        }
+                    }
+                }
+            }
+
+            let min = vec3i(key.0 * res as i32, key.1 * res as i32, key.2 * res as i32);
+
+            // Voxels outside of the tile are looked up in their own tile, only their
+            // presence matters for the faces of this tile
+            let at = |p: [i64; 3]| -> u16 {
+                if p.iter().zip(dims.iter()).any(|(v, d)| *v < 0 || *v >= *d as i64) {
+                    self.is_voxel_set(min + vec3i(p[0] as i32, p[1] as i32, p[2] as i32)) as u16
+                } else {
+                    grid[p[0] as usize + p[1] as usize * dims[0] + p[2] as usize * dims[0] * dims[1]]
+                }
+            };
+
+            self.mesh_faces(&mut mesh, dims, min, at);
+        }
+
+        mesh
+    }
+
+    /// Add the exposed faces of the voxels in the grid with the given dimensions to the mesh,
+    /// at(p) returns 0 for empty voxels and the color + 1 otherwise. The grid starts at the
+    /// global voxel coordinate min.
+    fn mesh_faces(&self, mesh: &mut Mesh, dims: [usize; 3], min: Vec3i, at: impl Fn([i64; 3]) -> u16) {
+        let scale = 1.0 / self.map.resolution as f32;
+        let origin = vec3f(min.x as f32, min.y as f32, min.z as f32);
+
+        for d in 0..3 {
+            let u = (d + 1) % 3;
+            let v = (d + 2) % 3;
+
+            for sign in [1_i64, -1] {
+                let mut normal = Vec3f::zero();
+                normal[d] = sign as f32;
+
+                let mut mask = vec![0_u16; dims[u] * dims[v]];
+
+                for slice in 0..dims[d] {
+
+                    // The exposed faces of this slice
+                    for j in 0..dims[v] {
+                        for i in 0..dims[u] {
+                            let mut p = [0_i64; 3];
+                            p[d] = slice as i64;
+                            p[u] = i as i64;
+                            p[v] = j as i64;
+                            let c = at(p);
+                            p[d] += sign;
+                            mask[i + j * dims[u]] = if c != 0 && at(p) == 0 { c } else { 0 };
+                        }
+                    }
+
+                    // Merge the faces into rectangles
+                    for j in 0..dims[v] {
+                        let mut i = 0;
+                        while i < dims[u] {
+                            let c = mask[i + j * dims[u]];
+                            if c == 0 {
+                                i += 1;
+                                continue;
+                            }
+
+                            let mut w = 1;
+                            while i + w < dims[u] && mask[i + w + j * dims[u]] == c {
+                                w += 1;
+                            }
+
+                            let mut h = 1;
+                            'grow: while j + h < dims[v] {
+                                for k in 0..w {
+                                    if mask[i + k + (j + h) * dims[u]] != c {
+                                        break 'grow;
+                                    }
+                                }
+                                h += 1;
+                            }
+
+                            for l in 0..h {
+                                for k in 0..w {
+                                    mask[i + k + (j + l) * dims[u]] = 0;
+                                }
+                            }
+
+                            let mut base = Vec3f::zero();
+                            base[d] = if sign > 0 { slice as f32 + 1.0 } else { slice as f32 };
+                            base[u] = i as f32;
+                            base[v] = j as f32;
+                            let mut du = Vec3f::zero();
+                            du[u] = w as f32;
+                            let mut dv = Vec3f::zero();
+                            dv[v] = h as f32;
+
+                            let corners = if sign > 0 {
+                                [base, base + du, base + du + dv, base + dv]
+                            } else {
+                                [base, base + dv, base + du + dv, base + du]
+                            };
+                            for corner in corners {
+                                mesh.vertices.push((corner + origin) * scale);
+                            }
+                            mesh.normals.push(normal);
+                            mesh.colors.push((c - 1) as u8);
+
+                            i += w;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mesh the map and write it to the given file, see Mesh::write()
+    pub fn export_mesh(&self, path: &str, palette: &Palette) -> Result<usize, RpuError> {
+        let mesh = self.greedy_mesh();
+        if mesh.quads() == 0 {
+            return Err(RpuError::new(ErrorKind::Io { path: path.into(), message: "The world is empty, nothing to export.".into() }));
+        }
+        mesh.write(path, palette)?;
+        Ok(mesh.quads())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    /// A world with the given voxels in the tile at the origin
+    fn world_with(voxels: &[(usize, usize, usize, u8)]) -> World {
+        let mut world = World::new();
+        let mut tile = Tile::new(world.map.resolution);
+        for (x, y, z, color) in voxels {
+            tile.set_voxel(*x, *y, *z, Some((*color, 0)));
+        }
+        world.set_tile(vec3i(0, 0, 0), tile);
+        world
+    }
+
+    #[test]
+    fn single_voxel_is_closed() {
+        let world = world_with(&[(1, 2, 3, 7)]);
+        let mesh = world.greedy_mesh();
+        let scale = 1.0 / world.map.resolution as f32;
+
+        assert_eq!(mesh.quads(), 6);
+        assert_eq!(mesh.vertices.len(), 24);
+        assert!(mesh.colors.iter().all(|c| *c == 7));
+
+        // One face in each direction, the normals cancel out
+        let sum = mesh.normals.iter().fold(Vec3f::zero(), |a, n| a + *n);
+        assert_eq!(sum, Vec3f::zero());
+        for axis in 0..3 {
+            assert_eq!(mesh.normals.iter().filter(|n| n[axis] != 0.0).count(), 2);
+        }
+
+        // Every corner lies on the voxel
+        for v in &mesh.vertices {
+            assert!(v.x == scale || v.x == 2.0 * scale);
+            assert!(v.y == 2.0 * scale || v.y == 3.0 * scale);
+            assert!(v.z == 3.0 * scale || v.z == 4.0 * scale);
+        }
+    }
+
+    #[test]
+    fn flat_faces_are_merged() {
+        let mut voxels = vec![];
+        for x in 0..4 {
+            for z in 0..3 {
+                voxels.push((x, 0, z, 5));
+            }
+        }
+        assert_eq!(world_with(&voxels).greedy_mesh().quads(), 6);
+
+        // Faces only merge within one color, the top, bottom and both z sides split in two
+        for v in voxels.iter_mut().filter(|v| v.0 >= 2) {
+            v.3 = 6;
+        }
+        assert_eq!(world_with(&voxels).greedy_mesh().quads(), 10);
+    }
+
+    #[test]
+    fn tiles_are_meshed_one_by_one() {
+        let mut world = world_with(&[(0, 0, 0, 1)]);
+        let res = world.map.resolution;
+
+        // Faces between the voxels of neighbouring tiles are hidden
+        let mut tile = Tile::new(res);
+        tile.set_voxel(res - 1, 0, 0, Some((2, 0)));
+        world.set_tile(vec3i(-1, 0, 0), tile);
+        let mesh = world.greedy_mesh();
+        assert_eq!(mesh.quads(), 10);
+        assert!((0..mesh.quads()).all(|quad| mesh.normals[quad].x == 0.0 || mesh.vertices[quad * 4].x != 0.0));
+
+        // Far apart tiles do not need a grid spanning the space between them
+        let mut tile = Tile::new(res);
+        tile.set_voxel(0, 0, 0, Some((3, 0)));
+        world.set_tile(vec3i(10000, -10000, 10000), tile);
+        let mesh = world.greedy_mesh();
+        assert_eq!(mesh.quads(), 16);
+        assert_eq!(mesh.colors.iter().filter(|c| **c == 3).count(), 6);
+    }
+
+    #[test]
+    fn glb_layout() {
+        let mesh = world_with(&[(0, 0, 0, 1), (1, 0, 0, 2)]).greedy_mesh();
+        let glb = mesh.to_glb(&Palette::new());
+        let u32_at = |offset: usize| u32::from_le_bytes(glb[offset..offset + 4].try_into().unwrap()) as usize;
+
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(u32_at(4), 2);
+        assert_eq!(u32_at(8), glb.len());
+        assert!(glb.len().is_multiple_of(4));
+
+        let json_length = u32_at(12);
+        assert_eq!(&glb[16..20], b"JSON");
+        assert!(json_length.is_multiple_of(4));
+
+        let bin = 20 + json_length;
+        let bin_length = u32_at(bin);
+        assert_eq!(&glb[bin + 4..bin + 8], b"BIN\0");
+        assert!(bin_length.is_multiple_of(4));
+        assert_eq!(bin + 8 + bin_length, glb.len());
+
+        let json: serde_json::Value = serde_json::from_slice(&glb[20..bin]).unwrap();
+        assert!(json["buffers"][0]["byteLength"].as_u64().unwrap() as usize <= bin_length);
+        assert_eq!(json["accessors"][0]["count"].as_u64().unwrap() as usize, mesh.vertices.len());
+        assert_eq!(json["accessors"][3]["count"].as_u64().unwrap() as usize, mesh.quads() * 6);
+    }
+}
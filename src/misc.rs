@@ -21,9 +21,64 @@ pub struct AABB {
 }
 
 impl AABB {
+    /// A box centered at 0, 0, 0 with the given half size
+    pub fn centered(half_size: Vec3f) -> Self {
+        Self {
+            min         : -half_size,
+            max         : half_size,
+        }
+    }
+
+    /// A box covering everything
+    pub fn infinite() -> Self {
+        Self {
+            min         : Vec3f::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            max         : Vec3f::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+        }
+    }
+
+    /// True if no side of the box is infinite
+    pub fn is_finite(&self) -> bool {
+        (0..3).all(|axis| self.min[axis].is_finite() && self.max[axis].is_finite())
+    }
+
     pub fn get_size(&self) -> Vec3f {
         self.max - self.min
     }
+
+    /// The box enclosing both boxes
+    pub fn merge(&self, other: &AABB) -> AABB {
+        AABB {
+            min         : min(self.min, other.min),
+            max         : max(self.max, other.max),
+        }
+    }
+
+    /// Enlarge the box by the given amount on each side
+    pub fn grow(&self, amount: f32) -> AABB {
+        AABB {
+            min         : self.min - amount,
+            max         : self.max + amount,
+        }
+    }
+
+    /// Clip the box to the given limits
+    pub fn clamp(&self, limits: &AABB) -> AABB {
+        AABB {
+            min         : max(self.min, limits.min),
+            max         : min(self.max, limits.max),
+        }
+    }
+
+    /// Replace the infinite sides of the box by the given limits, finite sides are kept
+    pub fn bound(&self, limits: &AABB) -> AABB {
+        let mut b = self.clone();
+        for axis in 0..3 {
+            if !b.min[axis].is_finite() { b.min[axis] = limits.min[axis]; }
+            if !b.max[axis].is_finite() { b.max[axis] = limits.max[axis]; }
+        }
+        b
+    }
 }
 
 use std::ops::Index;
@@ -156,6 +211,9 @@ pub struct Bake {
 
     pub location        : Location,
     pub facing          : Side,
+
+    /// Infinite shapes are clipped to this box
+    pub limit           : AABB,
 }
 
 impl Bake {
@@ -164,6 +222,64 @@ impl Bake {
             sdf         : None,
             location    : Location::FrontLeft,
             facing      : Side::Front,
+
+            limit       : AABB::centered(Vec3f::new(2.0, 2.0, 2.0)),
+        }
+    }
+}
+
+/// A spherical area light
+#[derive(PartialEq, Debug, Clone)]
+pub struct Light {
+    pub position        : Vec3f,
+    pub radius          : f32,
+    pub emission        : Vec3f,
+}
+
+impl Light {
+    pub fn new() -> Self {
+        Self {
+            position    : vec3f(2.0, 6.0, 3.0),
+            radius      : 1.0,
+            emission    : vec3f(40.0, 40.0, 40.0),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rotates the direction d into the cone with the given angles
+pub fn jitter(d: Vec3f, phi: f32, sina: f32, cosa: f32) -> Vec3f {
+    let w = normalize(d);
+    let u = normalize(cross(w.yzx(), w));
+    let v = cross(w, u);
+    (u * phi.cos() + v * phi.sin()) * sina + w * cosa
+}
+
+/// The direction at the spherical angles theta and phi around the normal
+pub fn angle_to_dir(n: Vec3f, theta: f32, phi: f32) -> Vec3f {
+    let w = normalize(n);
+    let u = normalize(cross(w.yzx(), w));
+    let v = cross(w, u);
+    normalize((u * theta.cos() + v * theta.sin()) * phi.sin() + w * phi.cos())
+}
+
+/// The GGX specular term for the view direction v and the light direction l
+pub fn ggx(n: Vec3f, v: Vec3f, l: Vec3f, roughness: f32, f0: f32) -> f32 {
+    let alpha = roughness * roughness;
+    let h = normalize(l - v);
+    let dot_lh = max(dot(l, h), 0.0);
+    let dot_nh = max(dot(n, h), 0.0);
+    let dot_nl = max(dot(n, l), 0.0);
+    let alpha_sqr = alpha * alpha;
+    let denom = dot_nh * dot_nh * (alpha_sqr - 1.0) + 1.0;
+    let d = alpha_sqr / (std::f32::consts::PI * denom * denom);
+    let f = f0 + (1.0 - f0) * (1.0 - dot_lh).powf(5.0);
+    let k = 0.5 * alpha;
+    let k2 = k * k;
+    dot_nl * d * f / (dot_lh * dot_lh * (1.0 - k2) + k2)
+}
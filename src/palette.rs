@@ -1,20 +1,379 @@
 use maths_rs::Vec3f;
+use rayon::{slice::ParallelSliceMut, iter::{IndexedParallelIterator, ParallelIterator}};
+
+/// Colors which can be used by name, they resolve to the closest color of the palette
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("BLACK", 0x000000), ("WHITE", 0xffffff), ("GRAY", 0x808080), ("GREY", 0x808080),
+    ("LIGHT-GRAY", 0xc0c0c0), ("DARK-GRAY", 0x404040), ("SILVER", 0xc0c0c0),
+    ("RED", 0xff0000), ("DARK-RED", 0x8b0000), ("MAROON", 0x800000), ("CRIMSON", 0xdc143c),
+    ("ORANGE", 0xff8800), ("YELLOW", 0xffff00), ("GOLD", 0xffd700), ("OLIVE", 0x808000),
+    ("GREEN", 0x00a000), ("LIME", 0x00ff00), ("DARK-GREEN", 0x006400), ("GRASS", 0x5a9e32),
+    ("LEAF", 0x3c7a28), ("MOSS", 0x6b7f3a), ("TEAL", 0x008080), ("CYAN", 0x00ffff),
+    ("BLUE", 0x0000ff), ("NAVY", 0x000080), ("SKY", 0x87ceeb), ("WATER", 0x2f6fb0),
+    ("PURPLE", 0x800080), ("VIOLET", 0x8f00ff), ("MAGENTA", 0xff00ff), ("PINK", 0xffc0cb),
+    ("BROWN", 0x8b4513), ("WOOD", 0x9c6b3c), ("BARK", 0x5b3a21), ("SAND", 0xe2c98f),
+    ("BEIGE", 0xf5f5dc), ("TAN", 0xd2b48c), ("CLAY", 0xb66a50), ("BRICK", 0xa0402d),
+    ("STONE", 0x8a8a82), ("ROCK", 0x6b6a64), ("SNOW", 0xf4f8fc), ("ICE", 0xbfe6f2),
+    ("LAVA", 0xe4501a), ("SKIN", 0xe8b996),
+];
+
+/// The RGB color of the given name, names are upper case
+pub fn named_color(name: &str) -> Option<[u8; 3]> {
+    NAMED_COLORS.iter().find(|(n, _)| *n == name).map(|(_, c)| [(c >> 16) as u8, (c >> 8) as u8, *c as u8])
+}
+
+/// Parse a "#rrggbb" or "#rgb" color
+pub fn parse_hex_color(hex: &str) -> Option<[u8; 3]> {
+    let digits = hex.strip_prefix('#')?;
+    let value = u32::from_str_radix(digits, 16).ok()?;
+    match digits.len() {
+        6 => Some([(value >> 16) as u8, (value >> 8) as u8, value as u8]),
+        3 => {
+            let expand = |v: u32| (v * 17) as u8;
+            Some([expand((value >> 8) & 0xf), expand((value >> 4) & 0xf), expand(value & 0xf)])
+        },
+        _ => None,
+    }
+}
+
+/// How the distance between two colors is measured when matching palette colors
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ColorMetric {
+    /// Euclidean distance of the sRGB values
+    Rgb,
+    /// sRGB distance with the squared differences weighted by luminance
+    Weighted,
+    /// CIELAB deltaE (CIE76)
+    Lab,
+    /// Euclidean distance in OKLab
+    OkLab,
+}
+
+impl ColorMetric {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "RGB" => Some(ColorMetric::Rgb),
+            "WEIGHTED" => Some(ColorMetric::Weighted),
+            "LAB" => Some(ColorMetric::Lab),
+            "OKLAB" => Some(ColorMetric::OkLab),
+            _ => None,
+        }
+    }
+
+    /// Transform an sRGB color into the space in which the metric is the euclidean distance
+    pub fn transform(&self, r: f32, g: f32, b: f32) -> [f32; 3] {
+        match self {
+            ColorMetric::Rgb => [r, g, b],
+            ColorMetric::Weighted => [r * 0.30f32.sqrt(), g * 0.59f32.sqrt(), b * 0.11f32.sqrt()],
+            ColorMetric::Lab => rgb_to_lab(r, g, b),
+            ColorMetric::OkLab => rgb_to_oklab(r, g, b),
+        }
+    }
+}
+
+/// How colors are dithered when the path tracer output is clipped to the palette
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Dither {
+    None,
+    /// A 4x4 Bayer matrix offsets the color before matching
+    Ordered,
+    /// The matching error is diffused to the neighboring pixels once the image is complete
+    FloydSteinberg,
+}
+
+impl Dither {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "NONE" | "OFF" => Some(Dither::None),
+            "ORDERED" | "BAYER" => Some(Dither::Ordered),
+            "FLOYD" | "FLOYD-STEINBERG" => Some(Dither::FloydSteinberg),
+            _ => None,
+        }
+    }
+}
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct Palette {
 
+    /// The name of the palette, i.e. its file name
+    pub name                        : String,
+
     pub colors                      : Vec<[u8; 4]>,
     pub colors_f                    : Vec<[f32; 4]>,
+
+    /// The metric used by closest()
+    metric                          : ColorMetric,
+    /// The colors in the space of the metric, ordered as a k-d tree
+    tree                            : Vec<(u8, [f32; 3])>,
 }
 
 impl Palette {
     pub fn new() -> Self {
         Self {
+            name                    : String::new(),
+
             colors                  : vec![],
             colors_f                : vec![],
+
+            metric                  : ColorMetric::Rgb,
+            tree                    : vec![],
+        }
+    }
+
+    pub fn metric(&self) -> ColorMetric {
+        self.metric
+    }
+
+    /// Set the metric used to match colors against the palette
+    pub fn set_metric(&mut self, metric: ColorMetric) {
+        self.metric = metric;
+        self.build_tree();
+    }
+
+    /// Rebuild the k-d tree of the colors in the space of the metric, call it once the colors are added
+    pub fn build_tree(&mut self) {
+        self.tree = self.colors_f.iter().enumerate().map(|(index, c)| (index as u8, self.metric.transform(c[0], c[1], c[2]))).collect();
+        Self::sort_tree(&mut self.tree, 0);
+    }
+
+    /// The median along the axis of the depth becomes the node in the middle of the slice
+    fn sort_tree(nodes: &mut [(u8, [f32; 3])], depth: usize) {
+        if nodes.len() > 1 {
+            let axis = depth % 3;
+            let mid = nodes.len() / 2;
+            nodes.select_nth_unstable_by(mid, |a, b| a.1[axis].total_cmp(&b.1[axis]));
+            let (left, right) = nodes.split_at_mut(mid);
+            Self::sort_tree(left, depth + 1);
+            Self::sort_tree(&mut right[1..], depth + 1);
+        }
+    }
+
+    /// Nearest neighbor search, ties go to the lower index
+    fn search_tree(nodes: &[(u8, [f32; 3])], depth: usize, p: &[f32; 3], best: &mut (f32, u8)) {
+        if nodes.is_empty() {
+            return;
+        }
+        let axis = depth % 3;
+        let mid = nodes.len() / 2;
+        let (index, c) = &nodes[mid];
+
+        let d = (p[0] - c[0]).powi(2) + (p[1] - c[1]).powi(2) + (p[2] - c[2]).powi(2);
+        if d < best.0 || (d == best.0 && *index < best.1) {
+            *best = (d, *index);
+        }
+
+        let diff = p[axis] - c[axis];
+        let (near, far) = if diff < 0.0 { (&nodes[..mid], &nodes[mid + 1..]) } else { (&nodes[mid + 1..], &nodes[..mid]) };
+        Self::search_tree(near, depth + 1, p, best);
+        if diff * diff <= best.0 {
+            Self::search_tree(far, depth + 1, p, best);
+        }
+    }
+
+    /// Nearest neighbor by comparing all colors, ties go to the lower index
+    fn search_linear(&self, p: &[f32; 3]) -> u8 {
+        let mut best = (f32::MAX, 0);
+        for (index, c) in self.colors_f.iter().enumerate() {
+            let c = self.metric.transform(c[0], c[1], c[2]);
+            let d = (p[0] - c[0]).powi(2) + (p[1] - c[1]).powi(2) + (p[2] - c[2]).powi(2);
+            if d < best.0 {
+                best = (d, index as u8);
+            }
+        }
+        best.1
+    }
+
+    /// Add a color, palettes hold at most 256 colors. Until build_tree() is called again
+    /// closest() compares all colors.
+    pub fn add(&mut self, r: u8, g: u8, b: u8) {
+        if self.colors.len() < 256 {
+            self.colors.push([r, g, b, 0xFF]);
+            self.colors_f.push([r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0]);
         }
     }
 
+    /// Load a palette file, the format is taken from the extension: Paint.NET (.txt),
+    /// GIMP (.gpl), JASC (.pal), Adobe swatch exchange (.ase), Adobe color swatch (.aco)
+    /// or an image (.png) whose distinct colors form the palette.
+    pub fn load(path: &str) -> Result<Palette, String> {
+        let data = std::fs::read(path).map_err(|err| err.to_string())?;
+        let lower = path.to_lowercase();
+        let text = || String::from_utf8_lossy(&data).to_string();
+
+        let mut palette = Palette::new();
+        if lower.ends_with(".txt") {
+            palette.load_from_txt(text());
+        } else
+        if lower.ends_with(".gpl") {
+            palette.load_from_gpl(&text())?;
+        } else
+        if lower.ends_with(".pal") {
+            palette.load_from_jasc(&text())?;
+        } else
+        if lower.ends_with(".ase") {
+            palette.load_from_ase(&data)?;
+        } else
+        if lower.ends_with(".aco") {
+            palette.load_from_aco(&data)?;
+        } else
+        if lower.ends_with(".png") {
+            palette.load_from_png(&data)?;
+        } else {
+            return Err("Unsupported palette format, use .txt, .gpl, .pal, .ase, .aco or .png.".into());
+        }
+
+        if palette.colors.is_empty() {
+            return Err("The palette contains no colors.".into());
+        }
+
+        palette.name = std::path::Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or(path.to_string());
+        palette.build_tree();
+        Ok(palette)
+    }
+
+    /// Load the palette from a GIMP GPL file
+    pub fn load_from_gpl(&mut self, gpl: &str) -> Result<(), String> {
+        let mut lines = gpl.lines();
+        if lines.next().map(|l| l.trim()) != Some("GIMP Palette") {
+            return Err("Not a GIMP palette.".into());
+        }
+
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+                continue;
+            }
+            let rgb : Vec<u8> = line.split_whitespace().take(3).filter_map(|v| v.parse::<u8>().ok()).collect();
+            if rgb.len() == 3 {
+                self.add(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the palette from a JASC PAL file
+    pub fn load_from_jasc(&mut self, pal: &str) -> Result<(), String> {
+        let mut lines = pal.lines().map(|l| l.trim());
+        if lines.next() != Some("JASC-PAL") {
+            return Err("Not a JASC palette.".into());
+        }
+
+        // Version and the number of colors
+        _ = lines.next();
+        let count = lines.next().and_then(|l| l.parse::<usize>().ok()).ok_or("Invalid JASC palette.")?;
+
+        for line in lines.take(count) {
+            let rgb : Vec<u8> = line.split_whitespace().filter_map(|v| v.parse::<u8>().ok()).collect();
+            if rgb.len() >= 3 {
+                self.add(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the palette from an Adobe swatch exchange (ASE) file
+    pub fn load_from_ase(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = BigEndian { data, pos: 0 };
+        if r.bytes(4)? != b"ASEF" {
+            return Err("Not an ASE file.".into());
+        }
+        r.bytes(4)?;
+        let blocks = r.u32()?;
+
+        for _ in 0..blocks {
+            let kind = r.u16()?;
+            let length = r.u32()? as usize;
+            let mut block = BigEndian { data: r.bytes(length)?, pos: 0 };
+
+            // Color entry, groups are flattened
+            if kind == 0x0001 {
+                let name_length = block.u16()? as usize;
+                block.bytes(name_length * 2)?;
+                let model = block.bytes(4)?;
+                let mut values = vec![];
+                let count = match model {
+                    b"RGB " | b"LAB " => 3,
+                    b"CMYK" => 4,
+                    _ => 1,
+                };
+                for _ in 0..count {
+                    values.push(f32::from_bits(block.u32()?));
+                }
+
+                let rgb = match model {
+                    b"RGB " => [values[0], values[1], values[2]],
+                    b"CMYK" => cmyk_to_rgb(values[0], values[1], values[2], values[3]),
+                    b"LAB " => lab_to_rgb(values[0] * 100.0, values[1], values[2]),
+                    _ => [values[0], values[0], values[0]],
+                };
+                self.add_f(rgb);
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the palette from an Adobe color swatch (ACO) file, version 1 or 2
+    pub fn load_from_aco(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = BigEndian { data, pos: 0 };
+        let version = r.u16()?;
+        if version != 1 && version != 2 {
+            return Err("Not an ACO file.".into());
+        }
+        let count = r.u16()?;
+
+        for _ in 0..count {
+            let space = r.u16()?;
+            let w = r.u16()? as f32;
+            let x = r.u16()? as f32;
+            let y = r.u16()? as f32;
+            let z = r.u16()? as f32;
+            if version == 2 {
+                r.u16()?;
+                let name_length = r.u32()? as usize;
+                r.bytes(name_length * 2)?;
+            }
+
+            let rgb = match space {
+                0 => [w / 65535.0, x / 65535.0, y / 65535.0],
+                1 => hsb_to_rgb(w / 65535.0, x / 65535.0, y / 65535.0),
+                2 => cmyk_to_rgb(1.0 - w / 65535.0, 1.0 - x / 65535.0, 1.0 - y / 65535.0, 1.0 - z / 65535.0),
+                7 => lab_to_rgb(w / 100.0, (x as u16 as i16) as f32 / 100.0, (y as u16 as i16) as f32 / 100.0),
+                8 => [w / 10000.0, w / 10000.0, w / 10000.0],
+                _ => continue,
+            };
+            self.add_f(rgb);
+        }
+        Ok(())
+    }
+
+    /// Load the palette from an image, every distinct color in reading order is added
+    pub fn load_from_png(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(|err| err.to_string())?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).map_err(|err| err.to_string())?;
+
+        let channels = info.color_type.samples();
+        let mut seen = std::collections::HashSet::new();
+        for pixel in pixels[..info.buffer_size()].chunks_exact(channels) {
+            let rgb = if channels < 3 { [pixel[0], pixel[0], pixel[0]] } else { [pixel[0], pixel[1], pixel[2]] };
+
+            // Fully transparent pixels are not part of the palette
+            let alpha = if channels == 2 || channels == 4 { pixel[channels - 1] } else { 255 };
+            if alpha > 0 && seen.insert(rgb) {
+                self.add(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a color given in the range 0..1
+    fn add_f(&mut self, rgb: [f32; 3]) {
+        let c = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        self.add(c(rgb[0]), c(rgb[1]), c(rgb[2]));
+    }
+
     /// Load the palette from a Paint.net TXT file
     pub fn load_from_txt(&mut self, txt: String) {
         for line in txt.lines() {
@@ -67,31 +426,35 @@ impl Palette {
                 let r = r.ok().unwrap();
                 let g = g.ok().unwrap();
                 let b = b.ok().unwrap();
-                self.colors.push([r, g, b, 0xFF]);
-                self.colors_f.push([r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0]);
+                self.add(r, g, b);
             }
         }
     }
 
+    /// Indices outside of smaller palettes wrap around
+    fn wrap(&self, index: u8) -> usize {
+        index as usize % self.colors_f.len().max(1)
+    }
+
     /// Get the u8 based color at the given index
     pub fn at(&self, index: u8) -> [u8; 4] {
-        self.colors[index as usize]
+        self.colors[self.wrap(index)]
     }
 
     /// Get the f32 based color at the given index
     pub fn at_f(&self, index: u8) -> [f32; 4] {
-        self.colors_f[index as usize]
+        self.colors_f[self.wrap(index)]
     }
 
     /// Get the f32 based color at the given index
     pub fn at_vec(&self, index: u8) -> Vec3f {
-        let c = self.colors_f[index as usize];
+        let c = self.colors_f[self.wrap(index)];
         Vec3f::new(c[0], c[1], c[2])
     }
 
     /// Get the f32 based color at the given index
     pub fn at_vec_to_linear(&self, index: u8) -> Vec3f {
-        let mut c = self.colors_f[index as usize];
+        let mut c = self.colors_f[self.wrap(index)];
         c[0] = c[0].powf(2.2);
         c[1] = c[1].powf(2.2);
         c[2] = c[2].powf(2.2);
@@ -100,36 +463,421 @@ impl Palette {
 
     /// Get the f32 based color at the given index and convrts it to linear space
     pub fn at_f_to_linear(&self, index: u8) -> [f32; 4] {
-        let mut c = self.colors_f[index as usize].clone();
+        let mut c = self.colors_f[self.wrap(index)].clone();
         c[0] = c[0].powf(2.2);
         c[1] = c[1].powf(2.2);
         c[2] = c[2].powf(2.2);
         c
     }
 
-    /// Returns the closest color index
+    /// Returns the index of the color closest to the given sRGB color under the metric
     pub fn closest(&self, r: f32, g: f32, b: f32) -> u8 {
-        let mut index = 0;
-        let mut d = f32::MAX;
+        let p = self.metric.transform(r, g, b);
+        if self.tree.len() != self.colors_f.len() {
+            return self.search_linear(&p);
+        }
+        let mut best = (f32::MAX, 0);
+        Self::search_tree(&self.tree, 0, &p, &mut best);
+        best.1
+    }
+
+    /// Returns the closest color index after offsetting the color by the Bayer threshold of the pixel
+    pub fn closest_ordered(&self, r: f32, g: f32, b: f32, x: usize, y: usize) -> u8 {
+        const BAYER: [[f32; 4]; 4] = [
+            [ 0.0,  8.0,  2.0, 10.0],
+            [12.0,  4.0, 14.0,  6.0],
+            [ 3.0, 11.0,  1.0,  9.0],
+            [15.0,  7.0, 13.0,  5.0],
+        ];
+
+        // The spread is about the distance between the colors of an evenly distributed palette
+        let spread = 1.0 / (self.colors_f.len().max(2) as f32).cbrt();
+        let offset = ((BAYER[y % 4][x % 4] + 0.5) / 16.0 - 0.5) * spread;
+        self.closest(r + offset, g + offset, b + offset)
+    }
+
+    /// Clip the linear RGBA pixels of a width wide image to the palette with the given dithering
+    pub fn quantize(&self, pixels: &mut [f32], width: usize, dither: Dither) {
+        if dither == Dither::FloydSteinberg {
+            self.dither_floyd_steinberg(pixels, width);
+            return;
+        }
 
-        for i in 0..self.colors_f.len() {
+        pixels
+            .par_chunks_exact_mut(4)
+            .enumerate()
+            .for_each(|(i, pixel)| {
+                let (r, g, b) = (pixel[0].max(0.0).powf(0.4545), pixel[1].max(0.0).powf(0.4545), pixel[2].max(0.0).powf(0.4545));
+                let index = if dither == Dither::Ordered {
+                    self.closest_ordered(r, g, b, i % width, i / width)
+                } else {
+                    self.closest(r, g, b)
+                };
+                let color = self.at_f_to_linear(index);
+                pixel[..3].copy_from_slice(&color[..3]);
+        });
+    }
+
+    /// Clip the linear RGBA pixels of a width wide image to the palette, diffusing the error of
+    /// every pixel to its neighbors (Floyd-Steinberg). The error is diffused in sRGB.
+    pub fn dither_floyd_steinberg(&self, pixels: &mut [f32], width: usize) {
+        let mut work : Vec<[f32; 3]> = pixels.chunks_exact(4).map(|c| [c[0].max(0.0).powf(0.4545), c[1].max(0.0).powf(0.4545), c[2].max(0.0).powf(0.4545)]).collect();
+        let height = work.len() / width.max(1);
 
-            // let dd =
-            //     ((r - self.colors_f[i][0]) * 0.30).powf(2.0) +
-            //     ((g - self.colors_f[i][1]) * 0.59).powf(2.0) +
-            //     ((b - self.colors_f[i][2]) * 0.11).powf(2.0);
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let old = work[i].map(|v| v.clamp(0.0, 1.0));
+                let index = self.closest(old[0], old[1], old[2]);
+                let new = self.at_f(index);
+                let error = [old[0] - new[0], old[1] - new[1], old[2] - new[2]];
 
-            let dd =
-                ((r - self.colors_f[i][0]) * 1.0).powf(2.0) +
-                ((g - self.colors_f[i][1]) * 1.0).powf(2.0) +
-                ((b - self.colors_f[i][2]) * 1.0).powf(2.0);
+                let mut diffuse = |dx: isize, dy: usize, weight: f32| {
+                    let nx = x as isize + dx;
+                    if nx >= 0 && (nx as usize) < width && y + dy < height {
+                        let n = &mut work[(y + dy) * width + nx as usize];
+                        for c in 0..3 {
+                            n[c] += error[c] * weight;
+                        }
+                    }
+                };
+                diffuse(1, 0, 7.0 / 16.0);
+                diffuse(-1, 1, 3.0 / 16.0);
+                diffuse(0, 1, 5.0 / 16.0);
+                diffuse(1, 1, 1.0 / 16.0);
 
-            if dd < d {
-                d = dd;
-                index = i as u8;
+                let color = self.at_f_to_linear(index);
+                pixels[i * 4..i * 4 + 3].copy_from_slice(&color[..3]);
             }
         }
+    }
+
+    /// Returns the index of the color closest to the given RGB color
+    pub fn closest_rgb(&self, rgb: [u8; 3]) -> u8 {
+        self.closest(rgb[0] as f32 / 255.0, rgb[1] as f32 / 255.0, rgb[2] as f32 / 255.0)
+    }
+}
+
+/// Reads big endian binary palette formats
+struct BigEndian<'a> {
+    data                : &'a [u8],
+    pos                 : usize,
+}
+
+impl<'a> BigEndian<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.pos + count > self.data.len() {
+            return Err("Unexpected end of file.".into());
+        }
+        let bytes = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+fn cmyk_to_rgb(c: f32, m: f32, y: f32, k: f32) -> [f32; 3] {
+    [(1.0 - c) * (1.0 - k), (1.0 - m) * (1.0 - k), (1.0 - y) * (1.0 - k)]
+}
+
+fn hsb_to_rgb(h: f32, s: f32, v: f32) -> [f32; 3] {
+    let h = (h * 6.0).rem_euclid(6.0);
+    let c = v * s;
+    let x = c * (1.0 - ((h % 2.0) - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    [r + m, g + m, b + m]
+}
+
+/// CIELAB (D65) to sRGB
+pub fn lab_to_rgb(l: f32, a: f32, b: f32) -> [f32; 3] {
+    let fy = (l + 16.0) / 116.0;
+    let fx = fy + a / 500.0;
+    let fz = fy - b / 200.0;
+    let f = |t: f32| if t > 6.0 / 29.0 { t * t * t } else { 3.0 * (6.0f32 / 29.0).powi(2) * (t - 4.0 / 29.0) };
+    let (x, y, z) = (0.95047 * f(fx), f(fy), 1.08883 * f(fz));
+
+    let r = 3.2406 * x - 1.5372 * y - 0.4986 * z;
+    let g = -0.9689 * x + 1.8758 * y + 0.0415 * z;
+    let b = 0.0557 * x - 0.2040 * y + 1.0570 * z;
+
+    let gamma = |v: f32| if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.max(0.0).powf(1.0 / 2.4) - 0.055 };
+    [gamma(r), gamma(g), gamma(b)]
+}
+
+/// sRGB to linear RGB
+fn to_linear(v: f32) -> f32 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+/// sRGB to CIELAB (D65)
+pub fn rgb_to_lab(r: f32, g: f32, b: f32) -> [f32; 3] {
+    let (r, g, b) = (to_linear(r), to_linear(g), to_linear(b));
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f32| if t > (6.0f32 / 29.0).powi(3) { t.cbrt() } else { t / (3.0 * (6.0f32 / 29.0).powi(2)) + 4.0 / 29.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// sRGB to OKLab
+pub fn rgb_to_oklab(r: f32, g: f32, b: f32) -> [f32; 3] {
+    let (r, g, b) = (to_linear(r), to_linear(g), to_linear(b));
+    let l = (0.4122215 * r + 0.5363325 * g + 0.05144599 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.107397 * b).cbrt();
+    let s = (0.08830246 * r + 0.2817188 * g + 0.6299787 * b).cbrt();
+    [
+        0.2104543 * l + 0.7936178 * m - 0.004072047 * s,
+        1.977998 * l - 2.428592 * m + 0.4505937 * s,
+        0.02590404 * l + 0.7827718 * m - 0.8086758 * s,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    /// A palette of the given number of random colors
+    fn random_palette(rng: &mut StdRng, count: usize, metric: ColorMetric) -> Palette {
+        let mut palette = Palette::new();
+        for _ in 0..count {
+            palette.add(rng.gen(), rng.gen(), rng.gen());
+        }
+        palette.set_metric(metric);
+        palette
+    }
+
+    /// A width x height image of the linear color of the sRGB value v
+    fn gray_image(v: f32, width: usize, height: usize) -> Vec<f32> {
+        let linear = v.powf(2.2);
+        (0..width * height).flat_map(|_| [linear, linear, linear, 1.0]).collect()
+    }
+
+    /// Black and white
+    fn black_and_white() -> Palette {
+        let mut palette = Palette::new();
+        palette.add(0, 0, 0);
+        palette.add(255, 255, 255);
+        palette.build_tree();
+        palette
+    }
 
-        index
+    /// The colors of the palette without alpha
+    fn rgb(palette: &Palette) -> Vec<[u8; 3]> {
+        palette.colors.iter().map(|c| [c[0], c[1], c[2]]).collect()
     }
-}
\ No newline at end of file
+
+    /// An ASE block of the given kind
+    fn ase_block(kind: u16, content: &[u8]) -> Vec<u8> {
+        [&kind.to_be_bytes()[..], &(content.len() as u32).to_be_bytes(), content].concat()
+    }
+
+    /// An ASE color entry named "a" with the given model and values
+    fn ase_color(model: &[u8; 4], values: &[f32]) -> Vec<u8> {
+        let mut content = vec![0, 2, 0, b'a', 0, 0];
+        content.extend(model);
+        for v in values {
+            content.extend(v.to_be_bytes());
+        }
+        // Global, spot or normal color
+        content.extend([0, 2]);
+        ase_block(0x0001, &content)
+    }
+
+    /// An ASE file, version 1.0, with the given blocks
+    fn ase_file(blocks: &[Vec<u8>]) -> Vec<u8> {
+        [&b"ASEF\0\x01\0\0"[..], &(blocks.len() as u32).to_be_bytes(), &blocks.concat()].concat()
+    }
+
+    /// An ACO file of the given version out of the u16 values following the version
+    fn aco_file(version: u16, values: &[u16]) -> Vec<u8> {
+        [version].iter().chain(values).flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn gpl() {
+        let mut palette = Palette::new();
+        let gpl = "GIMP Palette\nName: Test\nColumns: 4\n# A comment\n255 0 0 Red\n  0 255   0\tGreen\n\n1 2\nnot a color\n300 0 0\n";
+        assert_eq!(palette.load_from_gpl(gpl), Ok(()));
+        assert_eq!(rgb(&palette), vec![[255, 0, 0], [0, 255, 0]]);
+
+        assert!(Palette::new().load_from_gpl("JASC-PAL\n255 0 0\n").is_err());
+        assert!(Palette::new().load_from_gpl("").is_err());
+    }
+
+    #[test]
+    fn jasc() {
+        // Only the given number of colors is read
+        let mut palette = Palette::new();
+        assert_eq!(palette.load_from_jasc("JASC-PAL\r\n0100\r\n2\r\n255 0 0\r\n0 0 255\r\n9 9 9\r\n"), Ok(()));
+        assert_eq!(rgb(&palette), vec![[255, 0, 0], [0, 0, 255]]);
+
+        assert!(Palette::new().load_from_jasc("GIMP Palette\n0100\n1\n255 0 0\n").is_err());
+        assert!(Palette::new().load_from_jasc("JASC-PAL\n0100\nmany\n255 0 0\n").is_err());
+        assert!(Palette::new().load_from_jasc("JASC-PAL\n").is_err());
+    }
+
+    #[test]
+    fn ase() {
+        // Groups are flattened
+        let data = ase_file(&[
+            ase_block(0xC001, &[0, 2, 0, b'g', 0, 0]),
+            ase_color(b"RGB ", &[1.0, 0.0, 0.0]),
+            ase_color(b"CMYK", &[1.0, 0.0, 0.0, 0.0]),
+            ase_block(0xC002, &[]),
+            ase_color(b"Gray", &[0.5]),
+            ase_color(b"LAB ", &[1.0, 0.0, 0.0]),
+        ]);
+        let mut palette = Palette::new();
+        assert_eq!(palette.load_from_ase(&data), Ok(()));
+        assert_eq!(rgb(&palette), vec![[255, 0, 0], [0, 255, 255], [128, 128, 128], [255, 255, 255]]);
+
+        let mut wrong_magic = data.clone();
+        wrong_magic[3] = b'X';
+        assert!(Palette::new().load_from_ase(&wrong_magic).is_err());
+        assert_eq!(Palette::new().load_from_ase(&data[..data.len() - 3]), Err("Unexpected end of file.".into()));
+
+        // More blocks announced than present
+        let mut missing_block = data.clone();
+        missing_block[11] += 1;
+        assert!(Palette::new().load_from_ase(&missing_block).is_err());
+    }
+
+    #[test]
+    fn aco() {
+        // RGB, gray, HSB and an unsupported color space which is skipped
+        let data = aco_file(1, &[4, 0, 65535, 0, 0, 0, 8, 5000, 0, 0, 0, 1, 21845, 65535, 65535, 0, 3, 1, 2, 3, 4]);
+        let mut palette = Palette::new();
+        assert_eq!(palette.load_from_aco(&data), Ok(()));
+        assert_eq!(rgb(&palette), vec![[255, 0, 0], [128, 128, 128], [0, 255, 0]]);
+
+        // Version 2 adds a name to each color
+        let mut palette = Palette::new();
+        assert_eq!(palette.load_from_aco(&aco_file(2, &[1, 0, 0, 0, 65535, 0, 0, 0, 2, b'b' as u16, 0])), Ok(()));
+        assert_eq!(rgb(&palette), vec![[0, 0, 255]]);
+
+        assert!(Palette::new().load_from_aco(&aco_file(3, &[0])).is_err());
+        assert!(Palette::new().load_from_aco(&data[..data.len() - 1]).is_err());
+        assert!(Palette::new().load_from_aco(&[]).is_err());
+    }
+
+    #[test]
+    fn png() {
+        // Red, red, green and a transparent blue
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header().unwrap().write_image_data(&[255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 0]).unwrap();
+
+        let mut palette = Palette::new();
+        assert_eq!(palette.load_from_png(&data), Ok(()));
+        assert_eq!(rgb(&palette), vec![[255, 0, 0], [0, 255, 0]]);
+
+        assert!(Palette::new().load_from_png(b"not a png").is_err());
+        assert!(Palette::new().load_from_png(&data[..data.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn load_by_extension() {
+        let dir = std::env::temp_dir();
+        let file = |name: &str, content: &str| {
+            let path = dir.join(format!("rpu-palette-{}-{}", std::process::id(), name));
+            std::fs::write(&path, content).unwrap();
+            path.to_string_lossy().to_string()
+        };
+
+        let gpl = file("test.GPL", "GIMP Palette\n0 0 0\n255 255 255\n");
+        let empty = file("empty.gpl", "GIMP Palette\n");
+        let unknown = file("test.xyz", "");
+
+        let palette = Palette::load(&gpl).unwrap();
+        assert!(palette.name.ends_with("test.GPL"));
+        assert_eq!(palette.closest(0.9, 0.9, 0.9), 1);
+        assert_eq!(Palette::load(&empty).err(), Some("The palette contains no colors.".into()));
+        assert!(Palette::load(&unknown).err().unwrap().starts_with("Unsupported palette format"));
+        assert!(Palette::load(&dir.join("rpu-missing.gpl").to_string_lossy()).is_err());
+
+        // PALETTE-SHOW renders the swatches of the loaded palette instead of the preview
+        let mut rpu = RPU::new();
+        let mut buffer = ColorBuffer::new(32, 16);
+        assert_eq!(rpu.evaluate(&format!("PALETTE {} PALETTE-SHOW", gpl), &mut buffer, &mut vec![]), Ok(true));
+        assert_eq!(buffer.at(8, 8), rpu.context.palette.at_f_to_linear(0));
+        assert_eq!(buffer.at(24, 8), rpu.context.palette.at_f_to_linear(1));
+        assert_eq!(buffer.at(16, 0), [0.02, 0.02, 0.02, 1.0]);
+
+        for path in [gpl, empty, unknown] {
+            _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn tree_matches_linear_search() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for metric in [ColorMetric::Rgb, ColorMetric::Weighted, ColorMetric::Lab, ColorMetric::OkLab] {
+            for count in [1, 2, 3, 17, 256] {
+                let palette = random_palette(&mut rng, count, metric);
+                for _ in 0..500 {
+                    let (r, g, b) = (rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+                    let p = metric.transform(r, g, b);
+                    assert_eq!(palette.closest(r, g, b), palette.search_linear(&p), "{:?} {} {:?}", metric, count, (r, g, b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ordered_dither() {
+        let palette = black_and_white();
+        let (width, height) = (8, 8);
+        let mut pixels = gray_image(0.5, width, height);
+        palette.quantize(&mut pixels, width, Dither::Ordered);
+
+        // Only palette colors, half of them white, repeating with the 4x4 Bayer matrix
+        assert!(pixels.chunks_exact(4).all(|p| p[0] == p[1] && p[1] == p[2] && (p[0] == 0.0 || p[0] == 1.0)));
+        assert_eq!(pixels.chunks_exact(4).filter(|p| p[0] == 1.0).count(), width * height / 2);
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(pixels[(y * width + x) * 4], pixels[((y % 4) * width + x % 4) * 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn floyd_steinberg_dither() {
+        let palette = black_and_white();
+        let (width, height) = (32, 32);
+
+        for v in [0.25, 0.5, 0.75] {
+            let mut pixels = gray_image(v, width, height);
+            palette.quantize(&mut pixels, width, Dither::FloydSteinberg);
+
+            // The error is diffused in sRGB, the share of white pixels matches the gray value
+            assert!(pixels.chunks_exact(4).all(|p| p[0] == p[1] && p[1] == p[2] && (p[0] == 0.0 || p[0] == 1.0)));
+            let white = pixels.chunks_exact(4).filter(|p| p[0] == 1.0).count() as f32 / (width * height) as f32;
+            assert!((white - v).abs() < 0.02, "{} {}", v, white);
+        }
+
+        // Palette colors pass through unchanged
+        let mut pixels = gray_image(1.0, width, height);
+        palette.quantize(&mut pixels, width, Dither::FloydSteinberg);
+        assert!(pixels.chunks_exact(4).all(|p| p[0] == 1.0));
+    }
+}
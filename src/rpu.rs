@@ -1,5 +1,31 @@
 use crate::prelude::*;
 
+/// Words which are executed as built-in commands, the CSG operations are added by CSGOp::from_word()
+const BUILTIN_WORDS: &[&str] = &[
+    // Stack
+    "DUP", "DROP", "SWAP", "OVER", "ROT", ".S",
+    // Values and arrays
+    "VEC3", "INT", "FLOAT", "LEN", "NTH", "APPEND", "MAP", "EACH", "RANGE",
+    // Transforms and repetition
+    "TRANSLATE", "ROTATE", "ROTATE-AXIS", "SCALE", "MIRROR", "REPEAT-INFINITE", "REPEAT-LIMITED", "POLAR-REPEAT",
+    // World and rendering
+    "BAKE", "MEMORY", "RESOLUTION", "LIGHT", "SUN", "PALETTE-SHOW",
+];
+
+/// Words which read the following token, i.e. a path, as their argument
+const PARSING_WORDS: &[&str] = &[
+    "INCLUDE", "SAVE", "LOAD", "EXPORT-VOX", "IMPORT-VOX", "EXPORT-MESH", "EXPORT-SURFACE", "EXPORT-SURFACE-DC",
+    "RENDER", "AO", "SHADOWS", "PALETTE", "PALETTE-METRIC", "DITHER",
+];
+
+/// Words which are only allowed inside word definitions and quotations
+const CONTROL_WORDS: &[&str] = &[
+    "IF", "ELSE", "THEN", "DO", "LOOP", "+LOOP", "I", "J", "BEGIN", "UNTIL", "WHILE", "REPEAT",
+];
+
+/// An open quotation while parsing: the values, tokens and open arrays outside of it and its brace
+type OpenQuotation = (Vec<Value>, Vec<Token>, Token, Vec<Token>);
+
 pub struct RPU {
     pub world               : World,
     pub preview             : World,
@@ -9,7 +35,34 @@ pub struct RPU {
 
     pub dictionary          : FxHashMap<String, Vec<Value>>,
 
-    pub bake                : Option<Bake>,
+    /// The maximum nesting depth of dictionary words, quotations and INCLUDEs, 256 by default.
+    /// Deeper calls fail with ErrorKind::RecursionLimit instead of overflowing the native stack.
+    pub recursion_limit     : usize,
+    depth                   : usize,
+
+    /// Set when BAKE compiled a shape into the preview during the current input
+    baked                   : bool,
+
+    /// The error of the last processed input, if any
+    pub last_error          : Option<RpuError>,
+
+    /// The dictionary is kept in ./dictionary.json, set by reload() for interactive sessions
+    persistent              : bool,
+
+    /// Unfinished input which spans multiple lines
+    pending                 : String,
+
+    /// Set when a world was loaded or imported and the preview needs to be rendered
+    loaded                  : bool,
+
+    /// The name of the material being defined and the stack size at its start
+    material_definition     : Option<(String, usize)>,
+
+    /// The stack size at each open array
+    array_marks             : Vec<usize>,
+
+    /// Set by PALETTE-SHOW, the palette swatches are rendered instead of the preview
+    show_palette            : bool,
 }
 
 impl RPU {
@@ -31,132 +84,178 @@ impl RPU {
 
             dictionary,
 
-            bake            : None,
+            recursion_limit : 256,
+            depth           : 0,
+
+            baked           : false,
+
+            last_error      : None,
+
+            persistent      : false,
+
+            pending         : String::new(),
+
+            loaded          : false,
+
+            material_definition : None,
+
+            array_marks     : vec![],
+
+            show_palette    : false,
         }
     }
 
-    /// Reload all data
+    /// Reload all data, the dictionary is saved again after each definition
     pub fn reload(&mut self) {
+        self.persistent = true;
         self.load_dictionary();
     }
 
-    /// Process the given string
+    /// Process the given string. Input which ends inside of a word definition is kept
+    /// and processed together with the following lines.
     pub fn process(&mut self, input: String, buffer: &mut ColorBuffer) -> (bool, Vec<String>) {
 
-        let mut output_image = false;
-        let mut output_text = vec![];
-        let mut error = false;
+        let input = if self.pending.is_empty() {
+            input
+        } else {
+            std::mem::take(&mut self.pending) + "\n" + input.as_str()
+        };
+
+        if Self::is_inside_definition(&input) {
+            self.pending = input;
+            return (false, vec![]);
+        }
 
-        let rc = self.valuefy(input);
+        self.run(input, buffer)
+    }
 
-        if rc.is_err() {
-            return (false, vec![rc.err().unwrap()]);
+    /// Read and process the given script file
+    pub fn process_file(&mut self, path: &str, buffer: &mut ColorBuffer) -> (bool, Vec<String>) {
+        match std::fs::read_to_string(path) {
+            Ok(input) => {
+                self.run(input, buffer)
+            },
+            Err(err) => {
+                let error = RpuError::new(ErrorKind::Io { path: path.into(), message: err.to_string() });
+                let lines = error.render("");
+                self.last_error = Some(error);
+                (false, lines)
+            }
         }
+    }
 
-        let mut values = rc.ok().unwrap();
+    /// Returns true if previous input ended inside of a word definition and more input is expected
+    pub fn is_incomplete(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Checks if the input ends inside of a word definition
+    fn is_inside_definition(input: &str) -> bool {
+        let mut scanner = Scanner::new(input.into());
+        let mut inside = false;
+
+        loop {
+            let token = scanner.scan_token(false);
+            match token.kind {
+                TokenType::Colon => inside = true,
+                TokenType::Identifier if token.lexeme == "MATERIAL" => inside = true,
+                // Paths may contain a colon or semicolon, skip them like parse() does
+                TokenType::Identifier if PARSING_WORDS.contains(&token.lexeme.as_str()) => _ = scanner.scan_path(),
+                TokenType::Semicolon => inside = false,
+                TokenType::Eof => break,
+                _ => {}
+            }
+        }
+        inside
+    }
+
+    /// Run the given input
+    fn run(&mut self, input: String, buffer: &mut ColorBuffer) -> (bool, Vec<String>) {
+
+        let mut output_text = vec![];
+
+        match self.evaluate(&input, buffer, &mut output_text) {
+            Ok(output_image) => {
+                self.last_error = None;
+                (output_image, output_text)
+            },
+            Err(err) => {
+                output_text.append(&mut err.render(&input));
+                self.last_error = Some(err);
+                (false, output_text)
+            }
+        }
+    }
+
+    /// Evaluate the given input, returns true if an image was rendered into the buffer.
+    pub fn evaluate(&mut self, input: &str, buffer: &mut ColorBuffer, output_text: &mut Vec<String>) -> Result<bool, RpuError> {
+
+        let mut output_image = false;
+
+        let (values, tokens) = self.parse(input.to_string())?;
 
         // STACK Config
         if values.is_empty() == false && values[0] == Value::Config("STACK".to_string()) {
 
             if self.stack.is_empty() {
-                return (false, vec!["Stack is empty.".to_string()]);
+                output_text.push("Stack is empty.".to_string());
+                return Ok(false);
             }
 
-            let mut stack_messages = vec![];
             for (index, v) in self.stack.iter().enumerate() {
-                stack_messages.push(format!("{}. {}", index + 1, v.to_string()));
+                output_text.push(format!("{}. {}", index + 1, v.to_string()));
             }
-            return (false, stack_messages);
+            return Ok(false);
         }
 
         // DICT Config
         if values.is_empty() == false && values[0] == Value::Config("DICT".to_string()) {
 
             if self.dictionary.is_empty() {
-                return (false, vec!["Dictionary is empty.".to_string()]);
+                output_text.push("Dictionary is empty.".to_string());
+                return Ok(false);
             }
 
-            let mut dict_messages = vec![];
             for (word, values) in &self.dictionary {
                 let mut val = String::new();
                 for v in values {
                     val += v.to_string().as_str();
                     val += " ";
                 }
-                dict_messages.push(format!("{}: {}", word, val));
+                output_text.push(format!("{}: {}", word, val));
             }
-            return (false, dict_messages);
+            return Ok(false);
         }
 
-        // Word definition
-        if values.is_empty() == false && values[0] == Value::WordDefinitionStart() {
-            _ = values.remove(0);
+        if let Err(err) = self.execute_located(values, &tokens, output_text) {
+            self.baked = false;
+            self.loaded = false;
+            self.material_definition = None;
+            self.array_marks.clear();
+            self.show_palette = false;
+            return Err(err);
+        }
 
-            if values.len() > 2 {
-                let word = values.remove(0);
-                let word_end = values.remove(values.len()-1);
+        if self.baked {
+            output_image = true;
+            self.preview.render_passes(buffer, &self.context);
 
-                if word_end == Value::WordDefinitionEnd() {
-                    self.dictionary.insert(word.to_string(), values);
-                    self.save_dictionary();
-                    return (false, vec![format!("{} added to the dictionary.", word.to_string())]);
-                } else {
-                    return (false, vec![format!("Invalid word definition.")]);
-                }
-            } else {
-                return (false, vec![format!("Invalid word definition.")]);
-            }
+            self.stack = vec![];
         }
 
-        let mut cloned = values.clone();
-        self.stack.append(&mut cloned);
-        values = self.stack.clone();
-
-        loop {
-            if let Some(value) = values.pop() {
-                match value {
-                    Value::Shape3D(mut sdf) => {
-                        if let Some(compile) = &mut self.bake {
-
-                            let rc = sdf.read_properties(&mut values);
-                            if rc.is_err() {
-                                return (false, vec![rc.err().unwrap()]);
-                            }
+        self.baked = false;
 
-                            compile.sdf = Some(sdf);
-                        }
-                    },
-                    Value::Command(cmd) => {
-                        match cmd.as_str() {
-                            "BAKE" => {
-                                self.bake = Some(Bake::new());
-                            },
-                            _ => {
-                            }
-                        }
-                    }
-                    _ => {
-                        //return (false, format!("Unexpected Value: {}", )
-                    }
-                }
-            } else {
-                break;
-            }
+        if self.loaded && !output_image {
+            output_image = true;
+            self.preview.render_passes(buffer, &self.context);
         }
+        self.loaded = false;
 
-        if let Some(bake) = &self.bake {
+        if self.show_palette {
             output_image = true;
-            self.preview.clear();
-            self.preview.compile(bake, &mut self.context);
-
-            for i in 0..10 {
-                self.preview.render(buffer, &mut self.context, i);
-            }
-
-            self.stack = vec![];
+            self.render_palette(buffer);
         }
-
-        self.bake = None;
+        self.show_palette = false;
 
         /*
         loop {
@@ -219,71 +318,1023 @@ impl RPU {
             //output_text.push(format!("{:?}", token).to_string());
         }*/
 
-        (output_image, output_text)
+        Ok(output_image)
+    }
+
+    /// Execute the given values against the stack
+    pub fn execute(&mut self, values: Vec<Value>, output: &mut Vec<String>) -> Result<(), RpuError> {
+        self.execute_located(values, &[], output)
+    }
+
+    /// Execute the values, errors are located at the token of the value which caused them
+    fn execute_located(&mut self, values: Vec<Value>, tokens: &[Token], output: &mut Vec<String>) -> Result<(), RpuError> {
+
+        // The index and limit of the active DO loops. Like in Forth each word and quotation has
+        // its own loops, I and J do not see the loops of the word which called it.
+        let mut loops : Vec<(f32, f32)> = vec![];
+
+        let mut ip = 0;
+        while ip < values.len() {
+            let at = ip;
+            if let Err(mut err) = self.step(&values, &mut ip, &mut loops, output) {
+                if let Some(token) = tokens.get(at) {
+                    err.locate(token);
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Execute the value at the instruction pointer and advance it
+    fn step(&mut self, values: &[Value], ip: &mut usize, loops: &mut Vec<(f32, f32)>, output: &mut Vec<String>) -> Result<(), RpuError> {
+        let value = values[*ip].clone();
+        *ip += 1;
+
+        match value {
+            Value::WordDefinitionStart() => {
+                let end = values[*ip..].iter().position(|v| *v == Value::WordDefinitionEnd());
+                if let Some(end) = end {
+                    self.define(values[*ip..*ip + end].to_vec(), output)?;
+                    *ip += end + 1;
+                } else {
+                    return Err(RpuError::new(ErrorKind::UnterminatedDefinition));
+                }
+            },
+            Value::Shape3D(mut sdf) => {
+                sdf.read_properties(&mut self.stack)?;
+                self.stack.push(Value::Shape3D(sdf));
+            },
+            Value::Command(cmd) => {
+                match cmd.as_str() {
+                    "IF" => {
+                        if !self.pop_flag(&cmd)? {
+                            *ip = Self::find_forward(values, *ip, &["ELSE", "THEN"]) + 1;
+                        }
+                    },
+                    "ELSE" => {
+                        *ip = Self::find_forward(values, *ip, &["THEN"]) + 1;
+                    },
+                    "THEN" | "BEGIN" => {
+                    },
+                    "DO" => {
+                        let index = self.pop_number(&cmd)?;
+                        let limit = self.pop_number(&cmd)?;
+                        loops.push((index, limit));
+                    },
+                    "LOOP" | "+LOOP" => {
+                        let step = if cmd == "+LOOP" { self.pop_number(&cmd)? } else { 1.0 };
+                        if let Some((index, limit)) = loops.last_mut() {
+                            *index += step;
+                            if (step >= 0.0 && *index < *limit) || (step < 0.0 && *index >= *limit) {
+                                *ip = Self::find_backward(values, *ip - 1, "DO") + 1;
+                            } else {
+                                loops.pop();
+                            }
+                        }
+                    },
+                    "I" | "J" => {
+                        let depth = if cmd == "I" { 1 } else { 2 };
+                        if loops.len() < depth {
+                            return Err(RpuError::new(ErrorKind::OutsideLoop(cmd)));
+                        }
+                        let index = loops[loops.len() - depth].0;
+                        self.stack.push(if index.fract() == 0.0 { Value::Int(index as i64) } else { Value::Number(index) });
+                    },
+                    "UNTIL" => {
+                        if !self.pop_flag(&cmd)? {
+                            *ip = Self::find_backward(values, *ip - 1, "BEGIN") + 1;
+                        }
+                    },
+                    "WHILE" => {
+                        if !self.pop_flag(&cmd)? {
+                            *ip = Self::find_forward(values, *ip, &["REPEAT"]) + 1;
+                        }
+                    },
+                    "REPEAT" => {
+                        *ip = Self::find_backward(values, *ip - 1, "BEGIN") + 1;
+                    },
+                    // Words which execute code are kept out of execute_command, its large frame
+                    // would otherwise be on the native stack for each nested call
+                    "MAP" | "EACH" => {
+                        self.map(cmd, output)?;
+                    },
+                    "INCLUDE" => {
+                        let path = self.pop_string(&cmd)?;
+                        self.include(path, output)?;
+                    },
+                    _ => {
+                        self.execute_command(cmd, output)?;
+                    }
+                }
+            },
+            Value::Word(word) => {
+                self.execute_word(word, output)?;
+            },
+            _ => {
+                self.stack.push(value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the command opens a control structure
+    fn is_control_start(value: &Value) -> bool {
+        matches!(value, Value::Command(cmd) if cmd == "IF" || cmd == "DO" || cmd == "BEGIN")
+    }
+
+    /// Returns true if the command closes a control structure
+    fn is_control_end(value: &Value) -> bool {
+        matches!(value, Value::Command(cmd) if cmd == "THEN" || cmd == "LOOP" || cmd == "+LOOP" || cmd == "UNTIL" || cmd == "REPEAT")
+    }
+
+    /// Find the index of the next command out of targets on the same nesting level, starting at the given index
+    fn find_forward(values: &[Value], start: usize, targets: &[&str]) -> usize {
+        let mut level = 0;
+        for (index, value) in values.iter().enumerate().skip(start) {
+            if level == 0 {
+                if let Value::Command(cmd) = value {
+                    if targets.contains(&cmd.as_str()) {
+                        return index;
+                    }
+                }
+            }
+            if Self::is_control_start(value) {
+                level += 1;
+            } else
+            if Self::is_control_end(value) {
+                level -= 1;
+            }
+        }
+        values.len()
+    }
+
+    /// Find the index of the opening command of the control structure which is closed at the given index
+    fn find_backward(values: &[Value], end: usize, target: &str) -> usize {
+        let mut level = 0;
+        for index in (0..end).rev() {
+            let value = &values[index];
+            if level == 0 && *value == Value::Command(target.to_string()) {
+                return index;
+            }
+            if Self::is_control_end(value) {
+                level += 1;
+            } else
+            if Self::is_control_start(value) {
+                level -= 1;
+            }
+        }
+        0
+    }
+
+    /// Verify that all control structures of a word definition are balanced
+    fn check_control_flow(values: &[Value]) -> Result<(), RpuError> {
+        let mut open : Vec<&str> = vec![];
+
+        for value in values {
+            if let Value::Command(cmd) = value {
+                let cmd = cmd.as_str();
+                match cmd {
+                    "IF" | "DO" | "BEGIN" => {
+                        open.push(cmd);
+                    },
+                    "ELSE" | "THEN" => {
+                        if open.last() != Some(&"IF") {
+                            return Err(RpuError::new(ErrorKind::UnmatchedControl { word: cmd.into(), expected: "IF".into() }));
+                        }
+                        if cmd == "THEN" {
+                            open.pop();
+                        }
+                    },
+                    "LOOP" | "+LOOP" => {
+                        let opened = open.pop();
+                        if opened != Some("DO") {
+                            return Err(RpuError::new(ErrorKind::UnmatchedControl { word: cmd.into(), expected: "DO".into() }));
+                        }
+                    },
+                    "UNTIL" | "WHILE" | "REPEAT" => {
+                        if open.last() != Some(&"BEGIN") {
+                            return Err(RpuError::new(ErrorKind::UnmatchedControl { word: cmd.into(), expected: "BEGIN".into() }));
+                        }
+                        if cmd != "WHILE" {
+                            open.pop();
+                        }
+                    },
+                    _ => {
+                    }
+                }
+            }
+        }
+
+        if let Some(cmd) = open.pop() {
+            Err(RpuError::new(ErrorKind::UnterminatedControl(cmd.into())))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Add a word definition to the dictionary
+    fn define(&mut self, mut values: Vec<Value>, output: &mut Vec<String>) -> Result<(), RpuError> {
+        if values.len() < 2 {
+            return Err(RpuError::new(ErrorKind::InvalidDefinition));
+        }
+
+        let word = values.remove(0);
+        Self::check_control_flow(&values)?;
+
+        self.dictionary.insert(word.to_string(), values);
+        output.push(format!("{} added to the dictionary.", word.to_string()));
+
+        // The word stays defined for this session if the dictionary can not be written
+        if let Err(err) = self.save_dictionary() {
+            output.push(err.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Read the given file and execute it
+    fn include(&mut self, path: String, output: &mut Vec<String>) -> Result<(), RpuError> {
+        let input = match std::fs::read_to_string(&path) {
+            Ok(input) => input,
+            Err(err) => {
+                return Err(RpuError::new(ErrorKind::Io { path, message: err.to_string() }));
+            }
+        };
+
+        if self.depth >= self.recursion_limit {
+            return Err(RpuError::new(ErrorKind::RecursionLimit { limit: self.recursion_limit, word: "INCLUDE".into() }));
+        }
+
+        let (values, tokens) = self.parse(input.clone()).map_err(|err| err.in_file(&path, &input))?;
+
+        self.depth += 1;
+        let rc = self.execute_located(values, &tokens, output);
+        self.depth -= 1;
+
+        rc.map_err(|err| err.in_file(&path, &input))
+    }
+
+    /// Look up the word in the dictionary and execute its body
+    fn execute_word(&mut self, word: String, output: &mut Vec<String>) -> Result<(), RpuError> {
+        let body = if let Some(body) = self.dictionary.get(&word) {
+            body.clone()
+        } else
+        if let Some(material) = self.context.material(&word) {
+            // Material names attach the material to the shape on the stack
+            let mut sdf = self.pop_shape(&word)?;
+            sdf.set_material(material);
+            self.stack.push(Value::Shape3D(sdf));
+            return Ok(());
+        } else
+        if let Some(rgb) = named_color(&word).or_else(|| parse_hex_color(&word)) {
+            // Named colors are only used if no word or material has the name. Like hex colors they
+            // are matched against the palette at this point, so they follow PALETTE and PALETTE-METRIC.
+            self.stack.push(Value::Int(self.context.palette.closest_rgb(rgb) as i64));
+            return Ok(());
+        } else {
+            return Err(RpuError::new(ErrorKind::UnknownIdentifier(word)));
+        };
+
+        if self.depth >= self.recursion_limit {
+            return Err(RpuError::new(ErrorKind::RecursionLimit { limit: self.recursion_limit, word }));
+        }
+
+        self.depth += 1;
+        let rc = self.execute(body, output);
+        self.depth -= 1;
+
+        rc
+    }
+
+    /// Execute the body of a quotation
+    fn call(&mut self, body: Vec<Value>, output: &mut Vec<String>) -> Result<(), RpuError> {
+        if self.depth >= self.recursion_limit {
+            return Err(RpuError::new(ErrorKind::RecursionLimit { limit: self.recursion_limit, word: "quotation".into() }));
+        }
+
+        self.depth += 1;
+        let rc = self.execute(body, output);
+        self.depth -= 1;
+
+        rc
+    }
+
+    /// Execute the quotation for each value of the array, MAP collects the results
+    fn map(&mut self, cmd: String, output: &mut Vec<String>) -> Result<(), RpuError> {
+        let body = self.pop_quotation(&cmd)?;
+        let array = self.pop_array(&cmd)?;
+
+        let mut results = vec![];
+        for v in array {
+            let mark = self.stack.len();
+            self.stack.push(v);
+            self.call(body.clone(), output)?;
+            if cmd == "MAP" {
+                // Everything the quotation left on the stack becomes part of the result
+                results.append(&mut self.stack.split_off(mark.min(self.stack.len())));
+            }
+        }
+        if cmd == "MAP" {
+            self.stack.push(Value::Array(results));
+        }
+
+        Ok(())
+    }
+
+    /// Execute a built-in command
+    fn execute_command(&mut self, cmd: String, output: &mut Vec<String>) -> Result<(), RpuError> {
+        match cmd.as_str() {
+            "BAKE" => {
+                let sdf = self.pop_shape(&cmd)?;
+                let mut bake = Bake::new();
+                bake.sdf = Some(sdf);
+
+                // The first BAKE of an input starts a new world, unless one was loaded or
+                // imported before it. Later words like SAVE or EXPORT-VOX see the baked world.
+                if !self.baked && !self.loaded {
+                    self.preview.clear();
+                }
+                self.preview.compile(&bake, &mut self.context);
+                self.baked = true;
+            },
+            "UNION" | "DIFFERENCE" | "SUBTRACT" | "INTERSECT" | "SMOOTH-UNION" | "SMOOTH-DIFFERENCE" | "SMOOTH-SUBTRACT" | "SMOOTH-INTERSECT" => {
+                let mut op = CSGOp::from_word(&cmd).unwrap();
+                if op.is_smooth() {
+                    op = op.with_radius(self.pop_number(&cmd)?);
+                }
+
+                // An array of shapes is combined from left to right
+                if let Some(Value::Array(_)) = self.stack.last() {
+                    let mut shapes = vec![];
+                    for v in self.pop_array(&cmd)? {
+                        match v {
+                            Value::Shape3D(sdf) => shapes.push(sdf),
+                            _ => return Err(RpuError::type_mismatch("shape", &v, &format!("\"{}\"", cmd))),
+                        }
+                    }
+                    let mut shapes = shapes.into_iter();
+                    let first = shapes.next().ok_or(RpuError::wrong_value("array of shapes", &format!("\"{}\"", cmd)))?;
+                    let sdf = shapes.fold(first, |a, b| SDF3D::composite(op, a, b));
+                    self.stack.push(Value::Shape3D(sdf));
+                    return Ok(());
+                }
+
+                let b = self.pop_shape(&cmd)?;
+                let a = self.pop_shape(&cmd)?;
+                self.stack.push(Value::Shape3D(SDF3D::composite(op, a, b)));
+            },
+            "TRANSLATE" | "ROTATE" | "MIRROR" => {
+                let v = self.pop_vec3(&cmd)?;
+                let mut sdf = self.pop_shape(&cmd)?;
+                sdf.transform(match cmd.as_str() {
+                    "TRANSLATE" => Transform::Translate(v),
+                    "ROTATE" => Transform::Rotate(v),
+                    _ => Transform::Mirror(v),
+                });
+                self.stack.push(Value::Shape3D(sdf));
+            },
+            "ROTATE-AXIS" => {
+                let angle = self.pop_number(&cmd)?;
+                let axis = self.pop_vec3(&cmd)?;
+                let mut sdf = self.pop_shape(&cmd)?;
+                sdf.transform(Transform::RotateAxis(axis, angle));
+                self.stack.push(Value::Shape3D(sdf));
+            },
+            "REPEAT-INFINITE" => {
+                let spacing = self.pop_vec3(&cmd)?;
+                let mut sdf = self.pop_shape(&cmd)?;
+                sdf.transform(Transform::Repeat(spacing));
+                self.stack.push(Value::Shape3D(sdf));
+            },
+            "REPEAT-LIMITED" => {
+                let spacing = self.pop_number(&cmd)?;
+                let count = self.pop_vec3(&cmd)?;
+                let mut sdf = self.pop_shape(&cmd)?;
+                sdf.transform(Transform::RepeatLimited(count, spacing));
+                self.stack.push(Value::Shape3D(sdf));
+            },
+            "POLAR-REPEAT" => {
+                let count = self.pop_number(&cmd)?;
+                let mut sdf = self.pop_shape(&cmd)?;
+                sdf.transform(Transform::PolarRepeat(count));
+                self.stack.push(Value::Shape3D(sdf));
+            },
+            "SCALE" => {
+                let scale = self.pop_number(&cmd)?;
+                if scale <= 0.0 {
+                    return Err(RpuError::wrong_value("positive number", &format!("\"{}\"", cmd)));
+                }
+                let mut sdf = self.pop_shape(&cmd)?;
+                sdf.transform(Transform::Scale(scale));
+                self.stack.push(Value::Shape3D(sdf));
+            },
+            "RESOLUTION" => {
+                let resolution = self.pop_number(&cmd)?;
+                if !(1.0..=256.0).contains(&resolution) {
+                    return Err(RpuError::wrong_value("number between 1 and 256", &format!("\"{}\"", cmd)));
+                }
+                self.world.map.set_resolution(resolution as usize);
+                self.preview.map.set_resolution(resolution as usize);
+                output.push(format!("Resolution set to {} voxels per tile.", resolution as usize));
+            },
+            "MEMORY" => {
+                let mut keys : Vec<&(i32, i32, i32)> = self.preview.map.tiles.keys().collect();
+                keys.sort();
+                for key in keys {
+                    let tile = &self.preview.map.tiles[key];
+                    output.push(format!("Tile ({}, {}, {}): {:.1} KB ({:?})", key.0, key.1, key.2, tile.memory_usage() as f32 / 1024.0, tile.storage()));
+                }
+                output.push(format!("{} tiles, {:.1} KB total.", self.preview.map.tiles.len(), self.preview.map.memory_usage() as f32 / 1024.0));
+            },
+            "MATERIAL-START" => {
+                let name = self.pop_string(&cmd)?;
+                self.material_definition = Some((name, self.stack.len()));
+            },
+            "MATERIAL" => {
+                let (name, start) = self.material_definition.take().ok_or(RpuError::new(ErrorKind::InvalidDefinition))?;
+                if self.stack.len() < start + 3 || self.stack.len() > start + 5 {
+                    self.stack.truncate(start);
+                    return Err(RpuError::new(ErrorKind::InvalidDefinition));
+                }
+
+                // roughness metallic emission [transparency] [albedo]
+                let mut args = vec![];
+                for value in self.stack.split_off(start) {
+                    args.push(value.to_number().ok_or(RpuError::wrong_value("number", &format!("\"{}\"", cmd)))?);
+                }
+
+                let mut material = Material::named(&name, clamp(args[0], 0.0, 1.0), clamp(args[1], 0.0, 1.0), max(args[2], 0.0));
+                if let Some(transparency) = args.get(3) {
+                    material.transparency = clamp(*transparency, 0.0, 1.0);
+                }
+                if let Some(albedo) = args.get(4) {
+                    material.albedo = Some(clamp(*albedo, 0.0, 255.0) as u8);
+                }
+
+                let index = self.context.define_material(material).ok_or(RpuError::new(ErrorKind::MaterialLimit))?;
+                output.push(format!("Material {} defined ({}).", name, index));
+            },
+            "RENDER" => {
+                let mode = self.pop_string(&cmd)?;
+                match mode.to_uppercase().as_str() {
+                    "PATH" => {
+                        self.context.render_state = true;
+                        output.push("Rendering with the path tracer.".to_string());
+                    },
+                    "PREVIEW" => {
+                        self.context.render_state = false;
+                        self.context.shading = true;
+                        output.push("Rendering the shaded preview.".to_string());
+                    },
+                    "FLAT" => {
+                        self.context.render_state = false;
+                        self.context.shading = false;
+                        output.push("Rendering the flat palette colors.".to_string());
+                    },
+                    _ => return Err(RpuError::wrong_value("PATH, PREVIEW or FLAT", &format!("\"{}\"", cmd))),
+                }
+            },
+            "SUN" => {
+                let direction = self.pop_vec3(&cmd)?;
+                if length(direction) == 0.0 {
+                    return Err(RpuError::wrong_value("direction", &format!("\"{}\"", cmd)));
+                }
+                self.context.sun = direction;
+            },
+            "AO" | "SHADOWS" => {
+                let state = self.pop_string(&cmd)?;
+                let on = match state.to_uppercase().as_str() {
+                    "ON" => true,
+                    "OFF" => false,
+                    _ => return Err(RpuError::wrong_value("ON or OFF", &format!("\"{}\"", cmd))),
+                };
+                if cmd == "AO" {
+                    self.context.ao_state = on;
+                } else {
+                    self.context.shadows = on;
+                }
+            },
+            "LIGHT" => {
+                let intensity = self.pop_number(&cmd)?;
+                let radius = self.pop_number(&cmd)?;
+                if radius <= 0.0 {
+                    return Err(RpuError::wrong_value("positive number", &format!("\"{}\"", cmd)));
+                }
+                let position = self.pop_vec3(&cmd)?;
+                self.context.light = Light {
+                    position,
+                    radius,
+                    emission    : vec3f(intensity, intensity, intensity),
+                };
+            },
+            "SAVE" => {
+                let path = self.pop_string(&cmd)?;
+                self.preview.save(&path)?;
+                output.push(format!("World saved to \"{}\".", path));
+            },
+            "EXPORT-VOX" => {
+                let path = self.pop_string(&cmd)?;
+                self.preview.export_vox(&path, &self.context.palette)?;
+                output.push(format!("Exported to \"{}\".", path));
+            },
+            "EXPORT-MESH" => {
+                let path = self.pop_string(&cmd)?;
+                let quads = self.preview.export_mesh(&path, &self.context.palette)?;
+                output.push(format!("Exported {} quads to \"{}\".", quads, path));
+            },
+            "EXPORT-SURFACE" | "EXPORT-SURFACE-DC" => {
+                let path = self.pop_string(&cmd)?;
+                let sdf = self.pop_shape(&cmd)?;
+                let cell = 1.0 / self.preview.map.tile_size() as f32;
+                let mesh = if cmd == "EXPORT-SURFACE" {
+                    sdf.marching_cubes(cell, &Bake::new().limit)
+                } else {
+                    sdf.dual_contouring(cell, &Bake::new().limit)
+                };
+                mesh.write(&path)?;
+                output.push(format!("Exported {} triangles to \"{}\".", mesh.triangles.len(), path));
+            },
+            "IMPORT-VOX" => {
+                let path = self.pop_string(&cmd)?;
+                let count = self.preview.import_vox(&path, &self.context.palette)?;
+                output.push(format!("Imported {} voxels from \"{}\".", count, path));
+                self.loaded = true;
+            },
+            "LOAD" => {
+                let path = self.pop_string(&cmd)?;
+                self.preview = World::load(&path)?;
+                if !self.preview.palette.is_empty() && self.preview.palette != self.context.palette.name {
+                    output.push(format!("The world uses the palette \"{}\".", self.preview.palette));
+                }
+                // The material bytes of the voxels refer to the materials of the saved world
+                for (index, material) in self.preview.materials.iter().enumerate().filter(|(_, m)| !m.name.is_empty()) {
+                    if let Some(other) = self.context.material(&material.name) {
+                        self.context.materials[other as usize] = Material::new();
+                    }
+                    self.context.materials[index] = material.clone();
+                }
+                self.loaded = true;
+            },
+            "PALETTE" => {
+                let path = self.pop_string(&cmd)?;
+                let mut palette = Palette::load(&path).map_err(|message| RpuError::new(ErrorKind::Io { path: path.clone(), message }))?;
+                palette.set_metric(self.context.palette.metric());
+                output.push(format!("Palette \"{}\" loaded ({} colors).", palette.name, palette.colors.len()));
+                self.context.palette = palette;
+            },
+            "PALETTE-SHOW" => {
+                self.show_palette = true;
+            },
+            "PALETTE-METRIC" => {
+                let name = self.pop_string(&cmd)?;
+                let metric = ColorMetric::from_name(&name).ok_or_else(|| RpuError::wrong_value("RGB, WEIGHTED, LAB or OKLAB", &format!("\"{}\"", cmd)))?;
+                self.context.palette.set_metric(metric);
+            },
+            "DITHER" => {
+                let name = self.pop_string(&cmd)?;
+                self.context.dither = Dither::from_name(&name).ok_or_else(|| RpuError::wrong_value("NONE, ORDERED or FLOYD-STEINBERG", &format!("\"{}\"", cmd)))?;
+            },
+            "+" | "-" | "*" | "/" => {
+                let b = self.pop_value(&cmd)?;
+                let a = self.pop_value(&cmd)?;
+                let result = Self::arithmetic(&cmd, a, b)?;
+                self.stack.push(result);
+            },
+            "<" | ">" | "<=" | ">=" => {
+                let b = self.pop_number(&cmd)?;
+                let a = self.pop_number(&cmd)?;
+
+                let result = match cmd.as_str() {
+                    "<" => a < b,
+                    ">" => a > b,
+                    "<=" => a <= b,
+                    _ => a >= b,
+                };
+                self.stack.push(Value::Bool(result));
+            },
+            "=" | "<>" => {
+                let b = self.pop_value(&cmd)?;
+                let a = self.pop_value(&cmd)?;
+
+                // Integers and numbers compare by value
+                let equal = match (a.to_number(), b.to_number()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => a == b,
+                };
+                self.stack.push(Value::Bool(if cmd == "=" { equal } else { !equal }));
+            },
+            "[" => {
+                self.array_marks.push(self.stack.len());
+            },
+            "]" => {
+                let mark = self.array_marks.pop().ok_or_else(|| RpuError::new(ErrorKind::UnmatchedControl { word: cmd.clone(), expected: "[".into() }))?;
+                let mark = mark.min(self.stack.len());
+                let array = self.stack.split_off(mark);
+                self.stack.push(Value::Array(array));
+            },
+            "LEN" => {
+                let v = self.pop_value(&cmd)?;
+                let len = match &v {
+                    Value::Array(array) => array.len(),
+                    Value::String(string) => string.chars().count(),
+                    _ => return Err(RpuError::type_mismatch("array or string", &v, &format!("\"{}\"", cmd))),
+                };
+                self.stack.push(Value::Int(len as i64));
+            },
+            "NTH" => {
+                let index = self.pop_value(&cmd)?;
+                let index = index.to_int().ok_or(RpuError::type_mismatch("integer", &index, &format!("\"{}\"", cmd)))?;
+                let array = self.pop_array(&cmd)?;
+
+                // Negative indices count from the end
+                let at = if index < 0 { array.len() as i64 + index } else { index };
+                if at < 0 || at >= array.len() as i64 {
+                    return Err(RpuError::wrong_value(&format!("index between 0 and {}", array.len() as i64 - 1), &format!("\"{}\"", cmd)));
+                }
+                self.stack.push(array[at as usize].clone());
+            },
+            "APPEND" => {
+                let v = self.pop_value(&cmd)?;
+                let mut array = self.pop_array(&cmd)?;
+                array.push(v);
+                self.stack.push(Value::Array(array));
+            },
+            "RANGE" => {
+                let end = self.pop_number(&cmd)?;
+                let start = self.pop_value(&cmd)?;
+                let first = start.to_int().filter(|_| end.fract() == 0.0);
+                let start = start.to_number().ok_or(RpuError::type_mismatch("number", &start, &format!("\"{}\"", cmd)))?;
+                if end - start > 1_000_000.0 {
+                    return Err(RpuError::wrong_value("range of at most 1000000 values", &format!("\"{}\"", cmd)));
+                }
+
+                // Count with an integer, f32 steps of 1.0 stop advancing at 2^24
+                let count = (end - start).ceil().max(0.0) as i64;
+                let array = (0..count).map(|i| {
+                    if let Some(first) = first { Value::Int(first + i) } else { Value::Number(start + i as f32) }
+                }).collect();
+                self.stack.push(Value::Array(array));
+            },
+            "VEC3" => {
+                let z = self.pop_number(&cmd)?;
+                let y = self.pop_number(&cmd)?;
+                let x = self.pop_number(&cmd)?;
+                self.stack.push(Value::Vec3(vec3f(x, y, z)));
+            },
+            "INT" => {
+                let n = self.pop_number(&cmd)?;
+                self.stack.push(Value::Int(n.trunc() as i64));
+            },
+            "FLOAT" => {
+                let n = self.pop_number(&cmd)?;
+                self.stack.push(Value::Number(n));
+            },
+            "DUP" => {
+                let a = self.pop_value(&cmd)?;
+                self.stack.push(a.clone());
+                self.stack.push(a);
+            },
+            "DROP" => {
+                _ = self.pop_value(&cmd)?;
+            },
+            "SWAP" => {
+                let b = self.pop_value(&cmd)?;
+                let a = self.pop_value(&cmd)?;
+                self.stack.push(b);
+                self.stack.push(a);
+            },
+            "OVER" => {
+                let b = self.pop_value(&cmd)?;
+                let a = self.pop_value(&cmd)?;
+                self.stack.push(a.clone());
+                self.stack.push(b);
+                self.stack.push(a);
+            },
+            "ROT" => {
+                let c = self.pop_value(&cmd)?;
+                let b = self.pop_value(&cmd)?;
+                let a = self.pop_value(&cmd)?;
+                self.stack.push(b);
+                self.stack.push(c);
+                self.stack.push(a);
+            },
+            "." => {
+                let a = self.pop_value(&cmd)?;
+                output.push(a.to_string());
+            },
+            ".S" => {
+                let mut s = format!("<{}>", self.stack.len());
+                for v in &self.stack {
+                    s += " ";
+                    s += v.to_string().as_str();
+                }
+                output.push(s);
+            },
+            _ => {
+                return Err(RpuError::new(ErrorKind::UnknownCommand(cmd)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the arithmetic word to the two values. Integers stay integers except for the
+    /// division, vectors are combined per component or scaled by numbers, strings concatenate.
+    fn arithmetic(cmd: &str, a: Value, b: Value) -> Result<Value, RpuError> {
+        let target = format!("\"{}\"", cmd);
+
+        if cmd == "/" && b.to_number() == Some(0.0) {
+            return Err(RpuError::new(ErrorKind::DivisionByZero));
+        }
+
+        match (&a, &b) {
+            (Value::Int(x), Value::Int(y)) if cmd != "/" => {
+                let result = match cmd {
+                    "+" => x.checked_add(*y),
+                    "-" => x.checked_sub(*y),
+                    _ => x.checked_mul(*y),
+                };
+                if let Some(result) = result {
+                    return Ok(Value::Int(result));
+                }
+            },
+            (Value::String(x), Value::String(y)) if cmd == "+" => {
+                return Ok(Value::String(format!("{}{}", x, y)));
+            },
+            (Value::Vec3(_), _) | (_, Value::Vec3(_)) => {
+                let x = if let Value::Vec3(v) = a { v } else { Vec3f::one() * a.to_number().ok_or(RpuError::type_mismatch("vec3 or number", &a, &target))? };
+                let y = if let Value::Vec3(v) = b { v } else { Vec3f::one() * b.to_number().ok_or(RpuError::type_mismatch("vec3 or number", &b, &target))? };
+                if cmd == "/" && (y.x == 0.0 || y.y == 0.0 || y.z == 0.0) {
+                    return Err(RpuError::new(ErrorKind::DivisionByZero));
+                }
+                return Ok(Value::Vec3(match cmd {
+                    "+" => x + y,
+                    "-" => x - y,
+                    "*" => x * y,
+                    _ => x / y,
+                }));
+            },
+            _ => {}
+        }
+
+        let x = a.to_number().ok_or(RpuError::type_mismatch("number", &a, &target))?;
+        let y = b.to_number().ok_or(RpuError::type_mismatch("number", &b, &target))?;
+        Ok(Value::Number(match cmd {
+            "+" => x + y,
+            "-" => x - y,
+            "*" => x * y,
+            _ => x / y,
+        }))
+    }
+
+    /// Pop a value from the stack
+    fn pop_value(&mut self, word: &str) -> Result<Value, RpuError> {
+        if let Some(v) = self.stack.pop() {
+            Ok(v)
+        } else {
+            Err(RpuError::stack_underflow("value", &format!("\"{}\"", word)))
+        }
+    }
+
+    /// Pop a flag from the stack, every non-zero number is true
+    fn pop_flag(&mut self, word: &str) -> Result<bool, RpuError> {
+        if let Some(v) = self.stack.pop() {
+            v.to_bool().ok_or(RpuError::type_mismatch("bool", &v, &format!("\"{}\"", word)))
+        } else {
+            Err(RpuError::stack_underflow("bool", &format!("\"{}\"", word)))
+        }
+    }
+
+    /// Pop a number from the stack
+    fn pop_number(&mut self, word: &str) -> Result<f32, RpuError> {
+        if let Some(v) = self.stack.pop() {
+            if let Some(n) = v.to_number() {
+                Ok(n)
+            } else {
+                Err(RpuError::type_mismatch("number", &v, &format!("\"{}\"", word)))
+            }
+        } else {
+            Err(RpuError::stack_underflow("number", &format!("\"{}\"", word)))
+        }
+    }
+
+    /// Pop a vector from the stack, either a vec3 or three numbers
+    fn pop_vec3(&mut self, word: &str) -> Result<Vec3f, RpuError> {
+        if let Some(v) = self.stack.last().and_then(|v| v.to_vec3()) {
+            _ = self.stack.pop();
+            return Ok(v);
+        }
+        let z = self.pop_number(word)?;
+        let y = self.pop_number(word)?;
+        let x = self.pop_number(word)?;
+        Ok(vec3f(x, y, z))
+    }
+
+    /// Pop an array from the stack
+    fn pop_array(&mut self, word: &str) -> Result<Vec<Value>, RpuError> {
+        match self.stack.pop() {
+            Some(Value::Array(array)) => Ok(array),
+            Some(v) => Err(RpuError::type_mismatch("array", &v, &format!("\"{}\"", word))),
+            None => Err(RpuError::stack_underflow("array", &format!("\"{}\"", word))),
+        }
+    }
+
+    /// Pop a quotation from the stack
+    fn pop_quotation(&mut self, word: &str) -> Result<Vec<Value>, RpuError> {
+        match self.stack.pop() {
+            Some(Value::Quotation(body)) => Ok(body),
+            Some(v) => Err(RpuError::type_mismatch("quotation", &v, &format!("\"{}\"", word))),
+            None => Err(RpuError::stack_underflow("quotation", &format!("\"{}\"", word))),
+        }
+    }
+
+    /// Pop a shape from the stack
+    fn pop_shape(&mut self, word: &str) -> Result<SDF3D, RpuError> {
+        match self.stack.pop() {
+            Some(Value::Shape3D(sdf)) => Ok(sdf),
+            Some(v) => Err(RpuError::type_mismatch("shape", &v, &format!("\"{}\"", word))),
+            None => Err(RpuError::stack_underflow("shape", &format!("\"{}\"", word))),
+        }
+    }
+
+    /// Pop a string from the stack
+    fn pop_string(&mut self, word: &str) -> Result<String, RpuError> {
+        match self.stack.pop() {
+            Some(Value::String(string)) => Ok(string),
+            Some(v) => Err(RpuError::type_mismatch("string", &v, &format!("\"{}\"", word))),
+            None => Err(RpuError::stack_underflow("string", &format!("\"{}\"", word))),
+        }
+    }
+
+    /// Literals without a fraction are integers
+    fn number_literal(lexeme: &str) -> Value {
+        if let Ok(n) = lexeme.parse::<i64>() {
+            Value::Int(n)
+        } else {
+            Value::Number(lexeme.parse::<f32>().unwrap_or(0.0))
+        }
     }
 
     /// Create values out of the token stream
-    pub fn valuefy(&mut self, input: String) -> Result<Vec<Value>, String> {
-        let mut scanner = Scanner::new(input.trim().into());
+    pub fn valuefy(&mut self, input: String) -> Result<Vec<Value>, RpuError> {
+        Ok(self.parse(input)?.0)
+    }
+
+    /// Create values out of the token stream, together with the token of each value
+    fn parse(&mut self, input: String) -> Result<(Vec<Value>, Vec<Token>), RpuError> {
+        let mut scanner = Scanner::new(input);
         let mut values : Vec<Value> = vec![];
+        let mut tokens : Vec<Token> = vec![];
 
         let mut first_value: bool = true;
         let mut word_definition: bool = false;
+        let mut word_name: bool = false;
+        let mut material_definition: bool = false;
+
+        // The open arrays, and for each open quotation the outer values, tokens and arrays
+        let mut open_brackets : Vec<Token> = vec![];
+        let mut quotations : Vec<OpenQuotation> = vec![];
 
         loop {
             let token = scanner.scan_token(false);
             let kind = token.kind;
+            let position = token.clone();
 
             if kind == TokenType::Colon {
                 values.push(Value::WordDefinitionStart());
                 word_definition = true;
+                word_name = true;
+            } else
+            if kind == TokenType::Semicolon && material_definition {
+                values.push(Value::Command("MATERIAL".to_string()));
+                material_definition = false;
             } else
             if kind == TokenType::Semicolon {
                 values.push(Value::WordDefinitionEnd());
+                word_definition = false;
+            } else
+            if kind == TokenType::Plus || kind == TokenType::Minus || kind == TokenType::Star || kind == TokenType::Slash || kind == TokenType::Dot
+                || kind == TokenType::Less || kind == TokenType::Greater || kind == TokenType::LessEqual || kind == TokenType::GreaterEqual {
+                values.push(Value::Command(token.lexeme));
+            } else
+            if kind == TokenType::Equal || kind == TokenType::EqualEqual {
+                values.push(Value::Command("=".to_string()));
+            } else
+            if kind == TokenType::BangEqual {
+                values.push(Value::Command("<>".to_string()));
+            } else
+            if kind == TokenType::If || kind == TokenType::Else || kind == TokenType::While {
+                if !word_definition && quotations.is_empty() {
+                    return Err(RpuError::at(ErrorKind::CompileOnly(token.lexeme.clone()), &token));
+                }
+                values.push(Value::Command(token.lexeme));
             } else
             if kind == TokenType::Eof {
+                if material_definition {
+                    return Err(RpuError::new(ErrorKind::UnterminatedDefinition));
+                }
+                if let Some((_, _, open, _)) = quotations.first() {
+                    return Err(RpuError::at(ErrorKind::MissingBrace, open));
+                }
+                if let Some(open) = open_brackets.first() {
+                    return Err(RpuError::at(ErrorKind::MissingBracket, open));
+                }
                 break;
             } else
             if kind == TokenType::Number {
-                if let Some(n) = token.lexeme.parse::<f32>().ok() {
-                    values.push(Value::Number(n));
+                values.push(Self::number_literal(&token.lexeme));
+            } else
+            if kind == TokenType::String {
+                values.push(Value::String(token.lexeme[1..token.lexeme.len() - 1].to_string()));
+            } else
+            if kind == TokenType::Quotation {
+                return Err(RpuError::at(ErrorKind::UnterminatedString, &token));
+            } else
+            if kind == TokenType::HexColor {
+                if parse_hex_color(&token.lexeme).is_some() {
+                    // Matched against the palette when executed, like named colors
+                    values.push(Value::Word(token.lexeme));
+                } else {
+                    return Err(RpuError::at(ErrorKind::InvalidColor(token.lexeme.clone()), &token));
                 }
             } else
+            if kind == TokenType::True || kind == TokenType::False || (kind == TokenType::Identifier && (token.lexeme == "TRUE" || token.lexeme == "FALSE")) {
+                values.push(Value::Bool(token.lexeme == "TRUE"));
+            } else
             if kind == TokenType::LeftBracket {
-
-                // Array can contain numbers only so far
-                let mut array : Vec<Value> = vec![];
-
-                loop {
-                    let token = scanner.scan_token(false);
-                    let kind = token.kind;
-
-                    if kind == TokenType::RightBracket {
-                        break;
-                    } else
-                    if kind == TokenType::Number {
-                        if let Some(n) = token.lexeme.parse::<f32>().ok() {
-                            array.push(Value::Number(n));
-                        }
-                    } else
-                    if kind == TokenType::Eof {
-                        return Err("Missing ']' after Array.".to_string());
-                    } else {
-                        return Err(format!("Unknown token in Array: {}.", token.lexeme));
+                // Arrays are collected from the stack when executed, they can hold any value
+                open_brackets.push(token.clone());
+                values.push(Value::Command("[".to_string()));
+            } else
+            if kind == TokenType::RightBracket {
+                if open_brackets.pop().is_none() {
+                    return Err(RpuError::at(ErrorKind::UnexpectedToken(token.lexeme.clone()), &token));
+                }
+                values.push(Value::Command("]".to_string()));
+            } else
+            if kind == TokenType::LeftBrace {
+                // Arrays have to be closed inside the quotation they were opened in
+                quotations.push((std::mem::take(&mut values), std::mem::take(&mut tokens), token.clone(), std::mem::take(&mut open_brackets)));
+            } else
+            if kind == TokenType::RightBrace {
+                if let Some(open) = open_brackets.first() {
+                    return Err(RpuError::at(ErrorKind::MissingBracket, open));
+                }
+                if let Some((outer_values, outer_tokens, open, outer_brackets)) = quotations.pop() {
+                    let body = std::mem::replace(&mut values, outer_values);
+                    tokens = outer_tokens;
+                    open_brackets = outer_brackets;
+                    if let Err(mut err) = Self::check_control_flow(&body) {
+                        err.locate(&open);
+                        return Err(err);
                     }
+                    values.push(Value::Quotation(body));
+                    tokens.push(open);
+                } else {
+                    return Err(RpuError::at(ErrorKind::UnexpectedToken(token.lexeme.clone()), &token));
                 }
-                values.push(Value::Array(array));
             } else
             if kind == TokenType::Identifier {
-                if token.lexeme == "BOX" {
-                    let sdf = SDF3D::new(SDF3DType::Box);
-                    values.push(Value::Shape3D(sdf));
+                if word_name {
+                    values.push(Value::Config(token.lexeme));
+                    word_name = false;
                 } else
-                if token.lexeme == "SPHERE" {
-                    let sdf = SDF3D::new(SDF3DType::Sphere);
+                if let Some(sdf_type) = SDF3DType::from_word(&token.lexeme) {
+                    let sdf = SDF3D::new(sdf_type);
                     values.push(Value::Shape3D(sdf));
                 } else
-                if token.lexeme == "BAKE" {
+                if BUILTIN_WORDS.contains(&token.lexeme.as_str()) || CSGOp::from_word(&token.lexeme).is_some() {
+                    values.push(Value::Command(token.lexeme));
+                } else
+                if token.lexeme == "MATERIAL" {
+                    if word_definition || material_definition {
+                        return Err(RpuError::at(ErrorKind::InterpretOnly(token.lexeme.clone()), &token));
+                    }
+                    let name = scanner.scan_token(false);
+                    if name.kind != TokenType::Identifier {
+                        return Err(RpuError::at(ErrorKind::MissingArgument(token.lexeme.clone()), &token));
+                    }
+                    values.push(Value::String(name.lexeme));
+                    values.push(Value::Command("MATERIAL-START".to_string()));
+                    material_definition = true;
+                } else
+                if PARSING_WORDS.contains(&token.lexeme.as_str()) {
+                    if let Some(path) = scanner.scan_path() {
+                        values.push(Value::String(path));
+                        values.push(Value::Command(token.lexeme));
+                    } else {
+                        return Err(RpuError::at(ErrorKind::MissingArgument(token.lexeme.clone()), &token));
+                    }
+                } else
+
+                // Control flow
+                if CONTROL_WORDS.contains(&token.lexeme.as_str()) {
+                    if !word_definition && quotations.is_empty() {
+                        return Err(RpuError::at(ErrorKind::CompileOnly(token.lexeme.clone()), &token));
+                    }
                     values.push(Value::Command(token.lexeme));
                 } else
 
@@ -295,26 +1346,26 @@ impl RPU {
                     values.push(Value::Config("DICT".to_string()));
                 } else
 
-                // Word Definition ?
-                if word_definition {
-                    values.push(Value::Config(token.lexeme));
-                } else
-
-                // In Dictionary ?
-                if let Some(dict) = self.dictionary.get(&token.lexeme) {
-                    let mut cloned = dict.clone();
-                    values.append(&mut cloned);
+                // Dictionary words, materials and named colors are resolved when executed, which
+                // allows recursion and words which are defined later in the input or by an INCLUDE.
+                {
+                    values.push(Value::Word(token.lexeme.clone()));
                 }
+            } else
+            if kind == TokenType::SingeLineComment {
+                continue;
+            } else {
+                return Err(RpuError::at(ErrorKind::UnexpectedToken(token.lexeme.clone()), &token));
+            }
 
-                else {
-                    return Err(format!("Unknown identifier: {}", token.lexeme))
-                }
+            while tokens.len() < values.len() {
+                tokens.push(position.clone());
             }
 
             first_value = false;
         }
 
-        Ok(values)
+        Ok((values, tokens))
     }
 
     pub fn render_world(&mut self, buffer: &mut ColorBuffer) {
@@ -359,43 +1410,50 @@ impl RPU {
 
     }
 
+    /// Render the colors of the palette as a grid of swatches, row by row from the top left
     pub fn render_palette(&mut self, buffer: &mut ColorBuffer) {
 
         let width = buffer.width;
-        let height = buffer.height as f32;
+        let height = buffer.height;
 
-        // let screen = vec2f(buffer.width as f32, buffer.height as f32);
+        let palette = &self.context.palette;
+        let count = palette.colors.len();
+
+        let columns = count.clamp(1, 16);
+        let rows = count.div_ceil(columns).max(1);
 
-        // let time = (iteration as f32 * 1000.0 / 60.0) / 1000.0;
-        // let _start = self.get_time();
+        // Square swatches with a small gap between them, centered in the buffer
+        let size = (width / columns).min(height / rows).max(1);
+        let gap = (size / 10).max(1);
+        let offset_x = (width - (size * columns).min(width)) / 2;
+        let offset_y = (height - (size * rows).min(height)) / 2;
+
+        let background = [0.02, 0.02, 0.02, 1.0];
 
         buffer.pixels
             .par_rchunks_exact_mut(width * 4)
             .enumerate()
             .for_each(|(j, line)| {
-                for (i, pixel) in line.chunks_exact_mut(4).enumerate() {
+                // The chunks start at the bottom row
+                let y = height - 1 - j;
 
-                    #[inline(always)]
-                    pub fn _mix_color(a: &[f32], b: &[f32], v: f32) -> [f32; 4] {
-                        [   (1.0 - v) * a[0] + b[0] * v,
-                            (1.0 - v) * a[1] + b[1] * v,
-                            (1.0 - v) * a[2] + b[2] * v,
-                            (1.0 - v) * a[3] + b[3] * v ]
-                    }
+                for (x, pixel) in line.chunks_exact_mut(4).enumerate() {
+                    let mut color = background;
 
-                    let i = j * width + i;
+                    if x >= offset_x && y >= offset_y {
+                        let column = (x - offset_x) / size;
+                        let row = (y - offset_y) / size;
+                        let index = row * columns + column;
 
-                    let x = (i % width) as f32;
-                    let y = height - (i / width) as f32;
-
-                    let uv = vec2f(x / width as f32, 1.0 - (y / height));
-
-                    let color = [uv.x, uv.y, 0.0, 1.0];
+                        if column < columns && row < rows && index < count
+                            && (x - offset_x) % size >= gap && (y - offset_y) % size >= gap {
+                            color = palette.at_f_to_linear(index as u8);
+                        }
+                    }
 
                     pixel.copy_from_slice(&color);
                 }
         });
-
     }
 
     /// Load the dictionary from disk
@@ -408,9 +1466,255 @@ impl RPU {
     }
 
     /// Save the dictionary to disk
-    fn save_dictionary(&self) {
-        if let Some(json) = serde_json::to_string_pretty(&self.dictionary).ok() {
-            std::fs::write("dictionary.json", json).expect("Unable to write dictionary");
+    fn save_dictionary(&self) -> Result<(), RpuError> {
+        if self.persistent {
+            if let Some(json) = serde_json::to_string_pretty(&self.dictionary).ok() {
+                std::fs::write("dictionary.json", json).map_err(|err| RpuError::new(ErrorKind::Io { path: "dictionary.json".into(), message: err.to_string() }))?;
+            }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::{baked, evaluate};
+    use Value::*;
+
+    /// The stack after evaluating the input in a fresh interpreter
+    fn stack(input: &str) -> Vec<Value> {
+        match evaluate(input) {
+            Ok(rpu) => rpu.stack,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// The error kind of evaluating the input in a fresh interpreter
+    fn error(input: &str) -> ErrorKind {
+        evaluate(input).err().unwrap().kind
+    }
+
+    #[test]
+    fn stack_words() {
+        assert_eq!(stack("1 2 DUP"), vec![Int(1), Int(2), Int(2)]);
+        assert_eq!(stack("1 2 DROP"), vec![Int(1)]);
+        assert_eq!(stack("1 2 SWAP"), vec![Int(2), Int(1)]);
+        assert_eq!(stack("1 2 OVER"), vec![Int(1), Int(2), Int(1)]);
+        assert_eq!(stack("1 2 3 ROT"), vec![Int(2), Int(3), Int(1)]);
+
+        for (input, word) in [("DUP", "DUP"), ("DROP", "DROP"), ("1 SWAP", "SWAP"), ("1 OVER", "OVER"), ("1 2 ROT", "ROT"), (".", "."), ("1 +", "+")] {
+            assert_eq!(error(input), ErrorKind::StackUnderflow { expected: "value".into(), target: format!("\"{}\"", word) }, "{}", input);
+        }
+    }
+
+    #[test]
+    fn arithmetic_on_each_type() {
+        // Integers stay integers, except for divisions and on overflow
+        assert_eq!(stack("7 2 - 3 4 *"), vec![Int(5), Int(12)]);
+        assert_eq!(stack("7 2 /"), vec![Number(3.5)]);
+        assert!(matches!(stack("9223372036854775807 1 +").as_slice(), [Number(_)]));
+
+        assert_eq!(stack("1 0.5 + 0.5 2 *"), vec![Number(1.5), Number(1.0)]);
+        assert_eq!(stack("\"a\" \"b\" +"), vec![String("ab".into())]);
+        assert_eq!(stack("1 2 3 VEC3 2 *"), vec![Vec3(vec3f(2.0, 4.0, 6.0))]);
+        assert_eq!(stack("1 1 1 VEC3 1 2 3 VEC3 -"), vec![Vec3(vec3f(0.0, -1.0, -2.0))]);
+
+        assert_eq!(error("1 0.0 /"), ErrorKind::DivisionByZero);
+        assert_eq!(error("1 1 1 VEC3 1 0 1 VEC3 /"), ErrorKind::DivisionByZero);
+        assert_eq!(error("\"a\" 1 +"), ErrorKind::TypeMismatch { expected: "number".into(), found: "string".into(), target: "\"+\"".into() });
+        assert_eq!(error("\"a\" \"b\" -"), ErrorKind::TypeMismatch { expected: "number".into(), found: "string".into(), target: "\"-\"".into() });
+        assert_eq!(error("\"a\" 1 2 3 VEC3 *"), ErrorKind::TypeMismatch { expected: "vec3 or number".into(), found: "string".into(), target: "\"*\"".into() });
+    }
+
+    #[test]
+    fn words_are_looked_up_when_executed() {
+        // Redefining a word changes the words which call it
+        assert_eq!(stack(": a 1 ; : b a ; b : a 2 ; b"), vec![Int(1), Int(2)]);
+        assert_eq!(stack(": b a ; : a 3 ; b"), vec![Int(3)]);
+        assert_eq!(stack(": down DUP 0 > IF DUP 1 - down THEN ; 3 down"), vec![Int(3), Int(2), Int(1), Int(0)]);
+    }
+
+    #[test]
+    fn recursion_limit() {
+        // Debug builds need the 8 MB stack of the main thread for the default limit of 256 calls
+        let default_limit = std::thread::Builder::new().stack_size(8 << 20).spawn(|| {
+            (error(": r r ; r"), error(": r [ 1 ] { DROP r } MAP ; r"))
+        }).unwrap().join().unwrap();
+        assert_eq!(default_limit.0, ErrorKind::RecursionLimit { limit: 256, word: "R".into() });
+        assert!(matches!(default_limit.1, ErrorKind::RecursionLimit { limit: 256, .. }));
+
+        let down = |input: &str| {
+            let mut rpu = RPU::new();
+            rpu.recursion_limit = 3;
+            rpu.evaluate(&format!(": down DUP 0 > IF 1 - down THEN ; {}", input), &mut ColorBuffer::new(8, 8), &mut vec![])
+        };
+        assert!(down("2 down").is_ok());
+        assert_eq!(down("3 down").err().unwrap().kind, ErrorKind::RecursionLimit { limit: 3, word: "DOWN".into() });
+    }
+
+    #[test]
+    fn nested_conditionals() {
+        let sign = ": sign DUP 0 < IF DROP -1 ELSE 0 > IF 1 ELSE 0 THEN THEN ;";
+        assert_eq!(stack(&format!("{} -5 sign 7 sign 0 sign", sign)), vec![Int(-1), Int(1), Int(0)]);
+        assert_eq!(stack(": f IF 1 THEN 2 ; TRUE f FALSE f"), vec![Int(1), Int(2), Int(2)]);
+    }
+
+    #[test]
+    fn counted_loops() {
+        assert_eq!(stack(": f 4 0 DO I LOOP ; f"), vec![Int(0), Int(1), Int(2), Int(3)]);
+        assert_eq!(stack(": f 10 0 DO I 3 +LOOP ; f"), vec![Int(0), Int(3), Int(6), Int(9)]);
+        assert_eq!(stack(": f 0 3 DO I -1 +LOOP ; f"), vec![Int(3), Int(2), Int(1), Int(0)]);
+        assert_eq!(stack(": f 2 0 DO 2 0 DO J 10 * I + LOOP LOOP ; f"), vec![Int(0), Int(1), Int(10), Int(11)]);
+
+        // The loops of a calling word are not visible
+        assert_eq!(error(": inner J ; : f 2 0 DO 2 0 DO inner LOOP LOOP ; f"), ErrorKind::OutsideLoop("J".into()));
+        assert_eq!(error(": f I ; f"), ErrorKind::OutsideLoop("I".into()));
+    }
+
+    #[test]
+    fn indefinite_loops() {
+        assert_eq!(stack(": f 0 BEGIN 1 + DUP 5 >= UNTIL ; f"), vec![Int(5)]);
+        assert_eq!(stack(": f 0 BEGIN DUP 3 < WHILE 1 + REPEAT ; f"), vec![Int(3)]);
+        assert_eq!(stack(": f 10 BEGIN DUP 3 < WHILE 1 + REPEAT ; f"), vec![Int(10)]);
+    }
+
+    #[test]
+    fn control_flow_errors() {
+        let unmatched = |word: &str, expected: &str| ErrorKind::UnmatchedControl { word: word.into(), expected: expected.into() };
+        assert_eq!(error(": f 1 ELSE ;"), unmatched("ELSE", "IF"));
+        assert_eq!(error(": f LOOP ;"), unmatched("LOOP", "DO"));
+        assert_eq!(error(": f 1 IF 2 LOOP THEN ;"), unmatched("LOOP", "DO"));
+        assert_eq!(error(": f 1 UNTIL ;"), unmatched("UNTIL", "BEGIN"));
+        assert_eq!(error(": f 1 WHILE ;"), unmatched("WHILE", "BEGIN"));
+        assert_eq!(error(": f 1 IF ;"), ErrorKind::UnterminatedControl("IF".into()));
+        assert_eq!(error(": f BEGIN ;"), ErrorKind::UnterminatedControl("BEGIN".into()));
+        assert_eq!(error("{ 1 IF }"), ErrorKind::UnterminatedControl("IF".into()));
+
+        // Control words are only allowed inside word definitions and quotations
+        assert_eq!(error("1 IF 2 THEN"), ErrorKind::CompileOnly("IF".into()));
+        assert_eq!(error("3 0 DO I LOOP"), ErrorKind::CompileOnly("DO".into()));
+        assert_eq!(error("BEGIN 1 UNTIL"), ErrorKind::CompileOnly("BEGIN".into()));
+    }
+
+    #[test]
+    fn color_literals() {
+        let palette = RPU::new().context.palette;
+        let red = Int(palette.closest_rgb([255, 0, 0]) as i64);
+        assert_eq!(stack("#ff0000 #F00 red"), vec![red.clone(), red.clone(), red]);
+        assert_eq!(stack("#123456"), vec![Int(palette.closest_rgb([0x12, 0x34, 0x56]) as i64)]);
+
+        for invalid in ["#ff00", "#ff00000", "#f", "#ggg"] {
+            assert_eq!(error(invalid), ErrorKind::InvalidColor(invalid.to_uppercase()), "{}", invalid);
+        }
+
+        // Words and materials of the same name take precedence over named colors
+        assert_eq!(stack(": red 5 ; red"), vec![Int(5)]);
+        assert!(matches!(stack("MATERIAL red 0.5 0 0 ; 0.3 SPHERE red").as_slice(), [Shape3D(_)]));
+    }
+
+    #[test]
+    fn colors_follow_the_palette() {
+        let path = std::env::temp_dir().join(format!("rpu-colors-{}.gpl", std::process::id()));
+        std::fs::write(&path, "GIMP Palette\n0 0 0\n255 0 0\n").unwrap();
+
+        // Colors are matched when executed, the word uses the palette loaded after its definition
+        let mut rpu = RPU::new();
+        let mut output = vec![];
+        let input = format!(": c #ff0000 red ; PALETTE {} c", path.to_string_lossy());
+        let rc = rpu.evaluate(&input, &mut ColorBuffer::new(8, 8), &mut output);
+        _ = std::fs::remove_file(&path);
+        assert_eq!(rc, Ok(false));
+        assert_eq!(rpu.stack, vec![Int(1), Int(1)]);
+
+        // The dictionary shows the colors as written
+        let mut output = vec![];
+        _ = rpu.evaluate("DICT", &mut ColorBuffer::new(8, 8), &mut output);
+        assert_eq!(output, vec!["C: #FF0000 RED ".to_string()]);
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(stack("0 3 RANGE"), vec![Array(vec![Int(0), Int(1), Int(2)])]);
+        assert_eq!(stack("0.5 3 RANGE"), vec![Array(vec![Number(0.5), Number(1.5), Number(2.5)])]);
+        assert_eq!(stack("3 0 RANGE"), vec![Array(vec![])]);
+
+        // Integer counting keeps going where f32 steps of 1.0 stop at 2^24
+        assert_eq!(stack("16777216 16777220 RANGE"), vec![Array((16777216..16777220).map(Int).collect())]);
+        assert_eq!(stack("0 1000000 RANGE LEN"), vec![Int(1000000)]);
+        assert!(evaluate("0 1000001 RANGE").is_err());
+    }
+
+    #[test]
+    fn nested_arrays() {
+        assert_eq!(stack("[ 1 [ 2 [ 3 ] ] [ ] ]"), vec![Array(vec![Int(1), Array(vec![Int(2), Array(vec![Int(3)])]), Array(vec![])])]);
+        assert_eq!(stack("[ 1 [ 2 3 ] ] 1 NTH LEN"), vec![Int(2)]);
+        assert_eq!(stack("[ [ 1 ] ] [ 2 ] APPEND"), vec![Array(vec![Array(vec![Int(1)]), Array(vec![Int(2)])])]);
+    }
+
+    #[test]
+    fn quotations_are_values() {
+        assert!(matches!(stack("{ 1 + }").as_slice(), [Quotation(_)]));
+        assert_eq!(stack("[ { 1 + } ] LEN"), vec![Int(1)]);
+        assert_eq!(stack("[ 1 2 ] { 10 * } DUP DROP MAP"), vec![Array(vec![Int(10), Int(20)])]);
+        assert_eq!(stack(": twice { 2 * } ; [ 3 ] twice MAP"), vec![Array(vec![Int(6)])]);
+    }
+
+    #[test]
+    fn map_over_mixed_values() {
+        assert_eq!(stack("[ 1 2.5 \"a\" ] { DUP } MAP LEN"), vec![Int(6)]);
+        assert_eq!(stack("[ 1 2.5 ] { 2 * } MAP"), vec![Array(vec![Int(2), Number(5.0)])]);
+        assert_eq!(stack("[ 1 \"a\" 2 ] { DROP } MAP"), vec![Array(vec![])]);
+        assert!(evaluate("[ 1 \"a\" ] { 1 + } MAP").is_err());
+    }
+
+    #[test]
+    fn shapes_take_one_color_array() {
+        // Only the topmost array of palette indices below a shape holds its colors
+        let s = stack("[ 1 2 ] [ 7 ] 0.3 SPHERE");
+        assert_eq!(s.len(), 2);
+        assert_eq!(s[0], Array(vec![Int(1), Int(2)]));
+        let rpu = baked("[ 7 ] 0.3 SPHERE BAKE");
+        assert!(rpu.preview.global_voxels().iter().all(|(_, color)| *color == 7));
+
+        // Other arrays are values of their own and stay on the stack
+        for input in ["0.5 3 RANGE", "[ 0 0 0 VEC3 1 0 0 VEC3 ]", "[ 300 ]", "[ \"a\" ]", "[ ]"] {
+            let s = stack(&format!("{} 0.5 SPHERE", input));
+            assert_eq!(s.len(), 2, "{}", input);
+            assert_eq!(s[0], stack(input)[0]);
+            assert!(matches!(s[1], Shape3D(_)));
+        }
+    }
+
+    #[test]
+    fn nth() {
+        assert_eq!(stack("[ 10 20 30 ] 0 NTH"), vec![Int(10)]);
+        assert_eq!(stack("[ 10 20 30 ] -1 NTH"), vec![Int(30)]);
+        assert!(evaluate("[ 10 20 30 ] 3 NTH").is_err());
+        assert!(evaluate("[ 10 20 30 ] -4 NTH").is_err());
+        assert!(evaluate("[ ] 0 NTH").is_err());
+        assert!(evaluate("[ 10 ] 0.5 NTH").is_err());
+    }
+
+    #[test]
+    fn repl_continues_definitions() {
+        let mut rpu = RPU::new();
+        let mut buffer = ColorBuffer::new(8, 8);
+
+        _ = rpu.process(": add".into(), &mut buffer);
+        assert!(rpu.is_incomplete());
+        _ = rpu.process("+ ;".into(), &mut buffer);
+        assert!(!rpu.is_incomplete());
+        _ = rpu.process("1 2 add".into(), &mut buffer);
+        assert_eq!(rpu.stack, vec![Int(3)]);
+
+        // A colon in a path does not start a definition
+        let path = std::env::temp_dir().join(format!("rpu-repl-{}:b.rpu", std::process::id()));
+        std::fs::write(&path, "4 5 +").unwrap();
+        let (_, output) = rpu.process(format!("INCLUDE {}", path.to_string_lossy()), &mut buffer);
+        _ = std::fs::remove_file(&path);
+
+        assert!(!rpu.is_incomplete(), "{:?}", output);
+        assert_eq!(rpu.stack, vec![Int(3), Int(9)]);
+    }
+}
@@ -71,6 +71,7 @@ pub enum TokenType {
 pub struct Token {
     pub kind        : TokenType,
     pub line        : usize,
+    pub column      : usize,
     pub lexeme      : String,
     pub indent      : usize,
 }
@@ -82,6 +83,7 @@ impl Token {
             kind    : TokenType::Error,
             lexeme  : text,
             line    : 0,
+            column  : 0,
             indent  : 0,
         }
     }
@@ -94,6 +96,7 @@ pub struct Scanner {
     start           : usize,
     current         : usize,
     line            : usize,
+    line_start      : usize,
     indent          : usize,
 }
 
@@ -124,6 +127,7 @@ impl<'sourcecode> Scanner {
             start       : 0,
             current     : 0,
             line        : 1,
+            line_start  : 0,
             indent      : 0,
         }
     }
@@ -139,6 +143,7 @@ impl<'sourcecode> Scanner {
             b' ' if allow_whitespace => self.make_token(TokenType::Space),
             b'\n' if allow_whitespace => self.make_token(TokenType::LineFeed),
             b'-' if self.matches(b'-') => self.make_token(TokenType::CodeBlock),
+            b'-' if is_digit(self.peek()) => self.number(),
             b'(' => self.make_token(TokenType::LeftParen),
             b')' => self.make_token(TokenType::RightParen),
             b'{' => self.make_token(TokenType::LeftBrace),
@@ -148,8 +153,13 @@ impl<'sourcecode> Scanner {
             b'$' => self.make_token(TokenType::Dollar),
             b';' => self.make_token(TokenType::Semicolon),
             b',' => self.make_token(TokenType::Comma),
+            b'.' if self.peek().eq_ignore_ascii_case(&b'S') && !is_alpha(self.peek_next()) => {
+                self.advance();
+                self.make_token(TokenType::Identifier)
+            },
             b'.' => self.make_token(TokenType::Dot),
             b'-' => self.make_token(TokenType::Minus),
+            b'+' if is_alpha(self.peek()) => self.identifier(),
             b'+' => self.make_token(TokenType::Plus),
             b'\'' => self.make_token(TokenType::Apostrophe),
             b'/' if self.matches(b'/') => self.single_line_comment(),
@@ -160,10 +170,12 @@ impl<'sourcecode> Scanner {
             b'!' => self.make_token(TokenType::Bang),
             b'=' if self.matches(b'=') => self.make_token(TokenType::EqualEqual),
             b'=' => self.make_token(TokenType::Equal),
+            b'<' if self.matches(b'>') => self.make_token(TokenType::BangEqual),
             b'<' if self.matches(b'=') => self.make_token(TokenType::LessEqual),
             b'<' => self.make_token(TokenType::Less),
             b'>' if self.matches(b'=') => self.make_token(TokenType::GreaterEqual),
             b'>' => self.make_token(TokenType::Greater),
+            b'#' => self.hex_color(),
             b'"' => self.string(),
             b'`' => self.string2(),
             c if is_digit(c) => self.number(),
@@ -180,11 +192,18 @@ impl<'sourcecode> Scanner {
         self.code[self.start..self.current].to_string()
     }
 
+    /// The column of the token start in characters, counting the bytes which start a UTF-8 character
+    fn column(&self) -> usize {
+        self.code.as_bytes()[self.line_start..self.start].iter().filter(|b| (**b & 0xC0) != 0x80).count() + 1
+    }
+
     fn make_token(&self, kind: TokenType) -> Token {
         Token {
             kind,
-            lexeme      : self.lexeme().to_uppercase(),
+            // Strings keep their case
+            lexeme      : if kind == TokenType::String { self.lexeme() } else { self.lexeme().to_uppercase() },
             line        : self.line,
+            column      : self.column(),
             indent      : self.indent,
         }
     }
@@ -197,7 +216,7 @@ impl<'sourcecode> Scanner {
         }
     }
     pub fn peek_next(&self) -> u8 {
-        if self.current > self.code.len() - 2 {
+        if self.current + 1 >= self.code.len() {
             b'\0'
         } else {
             self.code.as_bytes()[self.current + 1]
@@ -209,6 +228,7 @@ impl<'sourcecode> Scanner {
             kind        : TokenType::Error,
             lexeme      : message,
             line        : self.line,
+            column      : self.column(),
             indent      : self.indent,
         }
     }
@@ -244,6 +264,7 @@ impl<'sourcecode> Scanner {
                 b'\n' => {
                     self.line += 1;
                     self.advance();
+                    self.line_start = self.current;
                     self.indent = 0;
                     after_lf = true;
                 }
@@ -267,6 +288,7 @@ impl<'sourcecode> Scanner {
                     string = self.code[start..self.current].to_string();
                     self.advance();
                     self.line += 1;
+                    self.line_start = self.current;
                     break;
                 },
                 _ => {
@@ -296,6 +318,7 @@ impl<'sourcecode> Scanner {
                     newline = true;
                     self.advance();
                     self.line += 1;
+                    self.line_start = self.current;
                     indent = 0;
                 },
                 _ => {
@@ -314,6 +337,31 @@ impl<'sourcecode> Scanner {
     }
 
 
+    /// Scan the next whitespace separated word or quoted string, keeping its case
+    pub fn scan_path(&mut self) -> Option<String> {
+        self.skip_whitespace(false);
+        self.start = self.current;
+
+        if self.is_at_end() {
+            return None;
+        }
+
+        if self.peek() == b'"' {
+            self.advance();
+            while self.peek() != b'"' && self.peek() != b'\n' && !self.is_at_end() {
+                self.advance();
+            }
+            let path = self.code[self.start + 1..self.current].to_string();
+            self.matches(b'"');
+            Some(path)
+        } else {
+            while !self.is_at_end() && !self.peek().is_ascii_whitespace() {
+                self.advance();
+            }
+            Some(self.lexeme())
+        }
+    }
+
     fn string(&mut self) -> Token {
         let b_current = self.current;
 
@@ -374,7 +422,7 @@ impl<'sourcecode> Scanner {
     }
 
     fn hex_color(&mut self) -> Token {
-        while self.is_at_end() == false && self.peek() != b'\n' {
+        while is_alpha(self.peek()) || is_digit(self.peek()) {
             self.advance();
         }
         self.make_token(TokenType::HexColor)
@@ -388,7 +436,7 @@ impl<'sourcecode> Scanner {
     }
 
     fn identifier(&mut self) -> Token {
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
+        while is_alpha(self.peek()) || is_digit(self.peek()) || (self.peek() == b'-' && is_alpha(self.peek_next())) {
             self.advance();
         }
         self.make_token(self.identifier_type())
@@ -3,19 +3,504 @@ use crate::prelude::*;
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
 pub enum SDF3DType {
     Box,
-    Sphere
+    Sphere,
+    Cylinder,
+    Capsule,
+    Torus,
+    Cone,
+    HexPrism,
+    RoundBox,
+    Ellipsoid,
+    Plane,
 }
 
-use SDF3DType::*;
+/// Describes a primitive shape: the word which creates it, the names of its parameters
+/// in the order they are pushed on the stack, its distance function and its bounding box.
+/// Both functions work in the local space of the shape, i.e. centered at 0, 0, 0.
+pub struct Primitive {
+    pub sdf_type                : SDF3DType,
+    pub name                    : &'static str,
+    pub word                    : &'static str,
+    pub params                  : &'static [&'static str],
+
+    pub distance                : fn(p: Vec3f, params: &[f32]) -> f32,
+    pub bbox                    : fn(params: &[f32]) -> AABB,
+    /// The index of the first invalid parameter together with the expected value
+    pub validate                : fn(params: &[f32]) -> Option<(usize, &'static str)>,
+}
+
+/// The first of the given parameters which is not positive
+fn not_positive(params: &[f32], indices: &[usize]) -> Option<(usize, &'static str)> {
+    indices.iter().find(|i| params[**i] <= 0.0).map(|i| (*i, "positive number"))
+}
+
+/// The primitive registry. Adding a primitive only needs a new SDF3DType and an entry here.
+pub static PRIMITIVES: [Primitive; 10] = [
+    Primitive {
+        sdf_type    : SDF3DType::Box,
+        name        : "Box",
+        word        : "BOX",
+        params      : &["width", "height", "depth"],
+        distance    : |p, params| {
+            let q = abs(p) - vec3f(params[0], params[1], params[2]) / 2.0;
+            length(max(q, Vec3f::zero())) + min(max(q.x, max(q.y, q.z)), 0.0)
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0], params[1], params[2]) / 2.0),
+        validate    : |params| not_positive(params, &[0, 1, 2]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::Sphere,
+        name        : "Sphere",
+        word        : "SPHERE",
+        params      : &["radius"],
+        distance    : |p, params| length(p) - params[0],
+        bbox        : |params| AABB::centered(Vec3f::new(params[0], params[0], params[0])),
+        validate    : |params| not_positive(params, &[0]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::Cylinder,
+        name        : "Cylinder",
+        word        : "CYLINDER",
+        params      : &["radius", "height"],
+        distance    : |p, params| {
+            let d = vec2f(length(p.xz()) - params[0], p.y.abs() - params[1] / 2.0);
+            min(max(d.x, d.y), 0.0) + length(max(d, Vec2f::zero()))
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0], params[1] / 2.0, params[0])),
+        validate    : |params| not_positive(params, &[0, 1]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::Capsule,
+        name        : "Capsule",
+        word        : "CAPSULE",
+        params      : &["radius", "height"],
+        distance    : |mut p, params| {
+            let a = max(params[1] / 2.0 - params[0], 0.0);
+            p.y -= clamp(p.y, -a, a);
+            length(p) - params[0]
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0], max(params[1] / 2.0, params[0]), params[0])),
+        validate    : |params| not_positive(params, &[0, 1]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::Torus,
+        name        : "Torus",
+        word        : "TORUS",
+        params      : &["radius", "thickness"],
+        distance    : |p, params| {
+            let q = vec2f(length(p.xz()) - params[0], p.y);
+            length(q) - params[1]
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0] + params[1], params[1], params[0] + params[1])),
+        validate    : |params| not_positive(params, &[0, 1]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::Cone,
+        name        : "Cone",
+        word        : "CONE",
+        params      : &["radius", "height"],
+        distance    : |p, params| {
+            // Capped cone with the base at the bottom and the tip at the top, see https://iquilezles.org/articles/distfunctions/
+            let r1 = params[0];
+            let h = params[1] / 2.0;
+            let q = vec2f(length(p.xz()), p.y);
+            let k1 = vec2f(0.0, h);
+            let k2 = vec2f(-r1, 2.0 * h);
+            let ca = vec2f(q.x - min(q.x, if q.y < 0.0 { r1 } else { 0.0 }), q.y.abs() - h);
+            let cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+            let s = if cb.x < 0.0 && ca.y < 0.0 { -1.0 } else { 1.0 };
+            s * min(dot(ca, ca), dot(cb, cb)).sqrt()
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0], params[1] / 2.0, params[0])),
+        validate    : |params| not_positive(params, &[0, 1]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::HexPrism,
+        name        : "HexPrism",
+        word        : "HEX-PRISM",
+        params      : &["radius", "height"],
+        distance    : |p, params| {
+            // Hexagon in the xz plane with the given inner radius, extruded along y
+            let k = vec3f(-0.8660254, 0.5, 0.57735);
+            let mut q = abs(vec3f(p.x, p.z, p.y));
+            let r = params[0];
+            let t = 2.0 * min(dot(k.xy(), q.xy()), 0.0);
+            q.x -= t * k.x;
+            q.y -= t * k.y;
+            let d = vec2f(
+                length(q.xy() - vec2f(clamp(q.x, -k.z * r, k.z * r), r)) * signum(q.y - r),
+                q.z - params[1] / 2.0);
+            min(max(d.x, d.y), 0.0) + length(max(d, Vec2f::zero()))
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0] * 1.1547, params[1] / 2.0, params[0] * 1.1547)),
+        validate    : |params| not_positive(params, &[0, 1]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::RoundBox,
+        name        : "RoundBox",
+        word        : "ROUND-BOX",
+        params      : &["width", "height", "depth", "radius"],
+        distance    : |p, params| {
+            let r = params[3];
+            let q = abs(p) - vec3f(params[0], params[1], params[2]) / 2.0 + r;
+            length(max(q, Vec3f::zero())) + min(max(q.x, max(q.y, q.z)), 0.0) - r
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0], params[1], params[2]) / 2.0),
+        validate    : |params| {
+            not_positive(params, &[0, 1, 2]).or_else(|| {
+                // The rounding can not be larger than half of the smallest side
+                let limit = min(params[0], min(params[1], params[2])) / 2.0;
+                if params[3] < 0.0 || params[3] > limit { Some((3, "number from 0 to half of the smallest side")) } else { None }
+            })
+        },
+    },
+    Primitive {
+        sdf_type    : SDF3DType::Ellipsoid,
+        name        : "Ellipsoid",
+        word        : "ELLIPSOID",
+        params      : &["width", "height", "depth"],
+        distance    : |p, params| {
+            let r = vec3f(params[0], params[1], params[2]) / 2.0;
+            let k0 = length(p / r);
+            let k1 = length(p / (r * r));
+            if k1 == 0.0 { -min(r.x, min(r.y, r.z)) } else { k0 * (k0 - 1.0) / k1 }
+        },
+        bbox        : |params| AABB::centered(vec3f(params[0], params[1], params[2]) / 2.0),
+        validate    : |params| not_positive(params, &[0, 1, 2]),
+    },
+    Primitive {
+        sdf_type    : SDF3DType::Plane,
+        name        : "Plane",
+        word        : "PLANE",
+        params      : &["normal x", "normal y", "normal z", "offset"],
+        distance    : |p, params| {
+            // The half-space below the plane
+            let n = vec3f(params[0], params[1], params[2]);
+            let l = length(n);
+            if l == 0.0 { f32::MAX } else { dot(p, n / l) - params[3] }
+        },
+        bbox        : |params| {
+            // Axis aligned planes are bounded on one side, everything else is infinite
+            let mut bbox = AABB::infinite();
+            let n = vec3f(params[0], params[1], params[2]);
+            let l = length(n);
+            for axis in 0..3 {
+                if l > 0.0 && (n[axis].abs() - l).abs() < 1e-6 {
+                    if n[axis] > 0.0 {
+                        bbox.max[axis] = params[3];
+                    } else {
+                        bbox.min[axis] = -params[3];
+                    }
+                }
+            }
+            bbox
+        },
+        validate    : |params| if params[..3].iter().all(|n| *n == 0.0) { Some((0, "normal which is not zero")) } else { None },
+    },
+];
+
+impl SDF3DType {
+    /// The registry entry of the primitive
+    pub fn primitive(&self) -> &'static Primitive {
+        PRIMITIVES.iter().find(|p| p.sdf_type == *self).unwrap()
+    }
+
+    /// Look up the primitive type for the given word
+    pub fn from_word(word: &str) -> Option<SDF3DType> {
+        PRIMITIVES.iter().find(|p| p.word == word).map(|p| p.sdf_type)
+    }
+}
+
+/// Boolean operations which combine two shapes, the smooth variants hold their blend radius
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum CSGOp {
+    Union,
+    Difference,
+    Intersect,
+    SmoothUnion(f32),
+    SmoothDifference(f32),
+    SmoothIntersect(f32),
+}
+
+impl CSGOp {
+    /// Look up the operation for the given word, smooth operations get their radius later
+    pub fn from_word(word: &str) -> Option<CSGOp> {
+        match word {
+            "UNION" => Some(CSGOp::Union),
+            "DIFFERENCE" | "SUBTRACT" => Some(CSGOp::Difference),
+            "INTERSECT" => Some(CSGOp::Intersect),
+            "SMOOTH-UNION" => Some(CSGOp::SmoothUnion(0.0)),
+            "SMOOTH-DIFFERENCE" | "SMOOTH-SUBTRACT" => Some(CSGOp::SmoothDifference(0.0)),
+            "SMOOTH-INTERSECT" => Some(CSGOp::SmoothIntersect(0.0)),
+            _ => None,
+        }
+    }
+
+    /// Returns true for the smooth variants
+    pub fn is_smooth(&self) -> bool {
+        matches!(self, CSGOp::SmoothUnion(_) | CSGOp::SmoothDifference(_) | CSGOp::SmoothIntersect(_))
+    }
+
+    /// Set the blend radius of a smooth operation
+    pub fn with_radius(self, k: f32) -> CSGOp {
+        match self {
+            CSGOp::SmoothUnion(_) => CSGOp::SmoothUnion(k),
+            CSGOp::SmoothDifference(_) => CSGOp::SmoothDifference(k),
+            CSGOp::SmoothIntersect(_) => CSGOp::SmoothIntersect(k),
+            _ => self,
+        }
+    }
+
+    /// Combine the distances of both shapes. Returns the distance and true if the
+    /// surface at this point belongs to the second shape.
+    pub fn combine(&self, a: f32, b: f32) -> (f32, bool) {
+        // Smooth operators, see https://iquilezles.org/articles/smin/
+        match self {
+            CSGOp::Union => (min(a, b), b < a),
+            CSGOp::Difference => (max(a, -b), -b > a),
+            CSGOp::Intersect => (max(a, b), b > a),
+            CSGOp::SmoothUnion(k) => {
+                if *k <= 0.0 { return CSGOp::Union.combine(a, b); }
+                let h = clamp(0.5 + 0.5 * (b - a) / k, 0.0, 1.0);
+                (b * (1.0 - h) + a * h - k * h * (1.0 - h), b < a)
+            },
+            CSGOp::SmoothDifference(k) => {
+                if *k <= 0.0 { return CSGOp::Difference.combine(a, b); }
+                let h = clamp(0.5 - 0.5 * (b + a) / k, 0.0, 1.0);
+                (a * (1.0 - h) - b * h + k * h * (1.0 - h), -b > a)
+            },
+            CSGOp::SmoothIntersect(k) => {
+                if *k <= 0.0 { return CSGOp::Intersect.combine(a, b); }
+                let h = clamp(0.5 - 0.5 * (b - a) / k, 0.0, 1.0);
+                (b * (1.0 - h) + a * h + k * h * (1.0 - h), b > a)
+            },
+        }
+    }
+
+    /// Combine the bounding boxes of both shapes
+    pub fn combine_bbox(&self, a: AABB, b: AABB) -> AABB {
+        match self {
+            CSGOp::Union => a.merge(&b),
+            // A smooth union can grow up to a quarter of the radius out of the union
+            CSGOp::SmoothUnion(k) => a.merge(&b).grow(max(*k, 0.0) / 4.0),
+            CSGOp::Difference | CSGOp::SmoothDifference(_) => a,
+            CSGOp::Intersect | CSGOp::SmoothIntersect(_) => a.clamp(&b),
+        }
+    }
+
+    /// The name of the operation
+    pub fn name(&self) -> &'static str {
+        match self {
+            CSGOp::Union => "Union",
+            CSGOp::Difference => "Difference",
+            CSGOp::Intersect => "Intersect",
+            CSGOp::SmoothUnion(_) => "SmoothUnion",
+            CSGOp::SmoothDifference(_) => "SmoothDifference",
+            CSGOp::SmoothIntersect(_) => "SmoothIntersect",
+        }
+    }
+}
+
+/// A transform of a shape. Angles are in degrees, rotations follow the right hand rule.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum Transform {
+    Translate(Vec3f),
+    /// Euler angles, applied around the x, then y, then z axis
+    Rotate(Vec3f),
+    /// Rotation around an arbitrary axis
+    RotateAxis(Vec3f, f32),
+    /// Uniform scale
+    Scale(f32),
+    /// Mirrors the shape at the plane through the origin with the given normal,
+    /// the shape keeps its original on the positive side of the plane
+    Mirror(Vec3f),
+    /// Infinite repetition with the given spacing per axis, 0 disables an axis
+    Repeat(Vec3f),
+    /// The given number of instances per axis, centered at the origin
+    RepeatLimited(Vec3f, f32),
+    /// Instances around the y axis
+    PolarRepeat(f32),
+}
+
+/// Rotate p around the normalized axis (Rodrigues' rotation formula)
+fn rotate_axis(p: Vec3f, axis: Vec3f, degrees: f32) -> Vec3f {
+    let (s, c) = degrees.to_radians().sin_cos();
+    p * c + cross(axis, p) * s + axis * dot(axis, p) * (1.0 - c)
+}
+
+/// Rotate p by the Euler angles
+fn rotate_euler(p: Vec3f, angles: Vec3f) -> Vec3f {
+    let p = rotate_axis(p, Vec3f::unit_x(), angles.x);
+    let p = rotate_axis(p, Vec3f::unit_y(), angles.y);
+    rotate_axis(p, Vec3f::unit_z(), angles.z)
+}
+
+/// Normalize the vector, returns None for a zero length vector
+fn direction(v: Vec3f) -> Option<Vec3f> {
+    let l = length(v);
+    if l > 0.0 { Some(v / l) } else { None }
+}
+
+impl Transform {
+    /// Map a point into the space of the transformed shape. Returns the point and the factor
+    /// for the distance.
+    pub fn inverse(&self, p: Vec3f) -> (Vec3f, f32) {
+        match self {
+            Transform::Translate(t) => (p - *t, 1.0),
+            Transform::Rotate(angles) => {
+                let p = rotate_axis(p, Vec3f::unit_z(), -angles.z);
+                let p = rotate_axis(p, Vec3f::unit_y(), -angles.y);
+                (rotate_axis(p, Vec3f::unit_x(), -angles.x), 1.0)
+            },
+            Transform::RotateAxis(axis, angle) => {
+                if let Some(axis) = direction(*axis) {
+                    (rotate_axis(p, axis, -angle), 1.0)
+                } else {
+                    (p, 1.0)
+                }
+            },
+            Transform::Scale(s) => (p / *s, *s),
+            Transform::Mirror(normal) => {
+                if let Some(n) = direction(*normal) {
+                    let d = dot(p, n);
+                    if d < 0.0 {
+                        return (p - n * 2.0 * d, 1.0);
+                    }
+                }
+                (p, 1.0)
+            },
+            Transform::Repeat(spacing) => {
+                let mut q = p;
+                for axis in 0..3 {
+                    let s = spacing[axis];
+                    if s > 0.0 {
+                        q[axis] = p[axis] - s * (p[axis] / s).round();
+                    }
+                }
+                (q, 1.0)
+            },
+            Transform::RepeatLimited(count, s) => {
+                let mut q = p;
+                if *s > 0.0 {
+                    for axis in 0..3 {
+                        let n = max(count[axis].floor(), 1.0);
+                        let offset = p[axis] + s * (n - 1.0) / 2.0;
+                        q[axis] = offset - s * clamp((offset / s).round(), 0.0, n - 1.0);
+                    }
+                }
+                (q, 1.0)
+            },
+            Transform::PolarRepeat(n) => {
+                let n = n.floor();
+                if n < 2.0 {
+                    return (p, 1.0);
+                }
+                let sector = std::f32::consts::TAU / n;
+                let a = p.z.atan2(p.x) + sector / 2.0;
+                let a = a - sector * (a / sector).floor() - sector / 2.0;
+                let r = length(p.xz());
+                (vec3f(a.cos() * r, p.y, a.sin() * r), 1.0)
+            }
+        }
+    }
+
+    /// Transform the bounding box by transforming its corners
+    pub fn apply_bbox(&self, bbox: AABB) -> AABB {
+        let corners: Vec<Vec3f> = (0..8).map(|i| vec3f(
+            bbox[i & 1].x,
+            bbox[(i >> 1) & 1].y,
+            bbox[(i >> 2) & 1].z)).collect();
+
+        let finite = corners.iter().all(|c| c.x.is_finite() && c.y.is_finite() && c.z.is_finite());
+
+        let enclose = |points: Vec<Vec3f>| -> AABB {
+            let mut b = AABB { min: points[0], max: points[0] };
+            for p in &points[1..] {
+                b = b.merge(&AABB { min: *p, max: *p });
+            }
+            b
+        };
+
+        match self {
+            Transform::Translate(t) => AABB { min: bbox.min + *t, max: bbox.max + *t },
+            Transform::Scale(s) => AABB { min: bbox.min * *s, max: bbox.max * *s },
+            Transform::Rotate(angles) => {
+                if !finite { return AABB::infinite(); }
+                enclose(corners.iter().map(|c| rotate_euler(*c, *angles)).collect())
+            },
+            Transform::RotateAxis(axis, angle) => {
+                if let Some(axis) = direction(*axis) {
+                    if !finite { return AABB::infinite(); }
+                    enclose(corners.iter().map(|c| rotate_axis(*c, axis, *angle)).collect())
+                } else {
+                    bbox
+                }
+            },
+            Transform::Mirror(normal) => {
+                if let Some(n) = direction(*normal) {
+                    if !finite { return AABB::infinite(); }
+                    let mirrored = enclose(corners.iter().map(|c| *c - n * 2.0 * dot(*c, n)).collect());
+                    bbox.merge(&mirrored)
+                } else {
+                    bbox
+                }
+            },
+            Transform::Repeat(spacing) => {
+                let mut b = bbox;
+                for axis in 0..3 {
+                    if spacing[axis] > 0.0 {
+                        b.min[axis] = f32::NEG_INFINITY;
+                        b.max[axis] = f32::INFINITY;
+                    }
+                }
+                b
+            },
+            Transform::RepeatLimited(count, s) => {
+                let mut b = bbox;
+                if *s > 0.0 {
+                    for axis in 0..3 {
+                        let extent = s * (max(count[axis].floor(), 1.0) - 1.0) / 2.0;
+                        b.min[axis] -= extent;
+                        b.max[axis] += extent;
+                    }
+                }
+                b
+            },
+            Transform::PolarRepeat(n) => {
+                if n.floor() < 2.0 {
+                    return bbox;
+                }
+                // Every instance lies within the circle around the y axis which encloses the box
+                let x = max(bbox.min.x.abs(), bbox.max.x.abs());
+                let z = max(bbox.min.z.abs(), bbox.max.z.abs());
+                let r = (x * x + z * z).sqrt();
+                AABB { min: vec3f(-r, bbox.min.y, -r), max: vec3f(r, bbox.max.y, r) }
+            }
+        }
+    }
+}
 
+/// A shape, either a primitive or a CSG node which combines its two children.
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct SDF3D {
     sdf_type                    : SDF3DType,
 
+    #[serde(default)]
+    params                      : Vec<f32>,
+    textures                    : Vec<Value>,
 
-    radius                      : f32,
-    size                        : Vec3f,
-    textures                    : Vec<Value>
+    #[serde(default)]
+    op                          : Option<CSGOp>,
+    #[serde(default)]
+    children                    : Vec<SDF3D>,
+
+    /// Applied in order, the last one is the outermost
+    #[serde(default)]
+    transforms                  : Vec<Transform>,
+
+    /// Index into the materials of the context
+    #[serde(default)]
+    material                    : u8,
 }
 
 impl SDF3D {
@@ -24,100 +509,164 @@ impl SDF3D {
         Self {
             sdf_type,
 
-            radius              : 0.5,
-            size                : Vec3f::zero(),
+            params              : vec![],
 
             textures            : vec![Value::Number(10.0)],
+
+            op                  : None,
+            children            : vec![],
+
+            transforms          : vec![],
+
+            material            : 0,
         }
     }
 
-    /// Return the distance to the SDF
-    pub fn distance(&self, p: Vec3f, position: Vec3f) -> f32 {
+    /// Create a CSG node out of the two shapes
+    pub fn composite(op: CSGOp, a: SDF3D, b: SDF3D) -> Self {
+        Self {
+            sdf_type            : a.sdf_type,
 
-        // fn op_rep_lim(p: Vec3f, s: f32, lima: Vec3f, limb: Vec3f ) -> Vec3f {
-        //     return p-s*clamp(round(p/s),lima,limb);
-        // }
+            params              : vec![],
 
-        let mut d: f32 = std::f32::MAX;
+            textures            : vec![],
 
-    	//vec2 q = p*6.0 - vec2(5.0,0.0);
-        //vec2 r = opRepLim(q,2.0,vec2(-1,-2),vec2(1,2));
+            op                  : Some(op),
+            children            : vec![a, b],
 
-        //p = op_rep_lim(p, 0.25, vec3f(-1.0, 0.0, -0.0), vec3f(1.0, 2.0, 0.0));
+            transforms          : vec![],
 
-        if self.sdf_type == Box {
-            let q = abs(p - position) - self.size / 2.0;
-            d = length(max(q,Vec3f::new(0.0, 0.0, 0.0))) + min(max(q.x,q.y),0.0);
-        } else
-        if self.sdf_type == Sphere {
-            d = length(p - position) - self.radius;
+            material            : 0,
         }
-        d
     }
 
-    /// Read the properties of the SDF from the stack.
-    pub fn read_properties(&mut self, stack:  &mut Vec<Value>) -> Result<(), String> {
+    /// Add a transform on top of the existing ones
+    pub fn transform(&mut self, transform: Transform) {
+        self.transforms.push(transform);
+    }
 
-        if self.sdf_type == Box {
-            if let Some(v) = stack.pop() {
-                if let Some(n) = v.to_number() {
-                    self.size.z = n;
-                } else {
-                    return Err("Wrong value on stack. Expected number for \"depth\" of Box.".into());
-                }
-            } else {
-                return Err("Stack is empty. Expected number for \"depth\" of Box.".into());
+    /// True if the shape or one of its children was moved with TRANSLATE
+    pub fn is_translated(&self) -> bool {
+        self.transforms.iter().any(|t| matches!(t, Transform::Translate(_))) || self.children.iter().any(|c| c.is_translated())
+    }
+
+    /// The number of primitives in the tree
+    pub fn leaf_count(&self) -> usize {
+        if self.op.is_some() {
+            self.children.iter().map(|c| c.leaf_count()).sum()
+        } else {
+            1
+        }
+    }
+
+    /// Return the distance to the SDF
+    pub fn distance(&self, p: Vec3f, position: Vec3f) -> f32 {
+        self.distance_leaf(p - position).0
+    }
+
+    /// Return the local distance together with the index of the primitive which forms the surface
+    pub fn distance_leaf(&self, mut p: Vec3f) -> (f32, usize) {
+        if !self.transforms.is_empty() {
+            let mut scale = 1.0;
+            for transform in self.transforms.iter().rev() {
+                let (q, s) = transform.inverse(p);
+                p = q;
+                scale *= s;
             }
-            if let Some(v) = stack.pop() {
-                if let Some(n) = v.to_number() {
-                    self.size.y = n;
-                } else {
-                    return Err("Wrong value on stack. Expected number for \"height\" of Box.".into());
-                }
+            let (d, leaf) = self.distance_untransformed(p);
+            return (if d == f32::MAX { d } else { d * scale }, leaf);
+        }
+
+        self.distance_untransformed(p)
+    }
+
+    /// The distance of the shape without its transforms
+    fn distance_untransformed(&self, p: Vec3f) -> (f32, usize) {
+        if let Some(op) = &self.op {
+            let (a, a_leaf) = self.children[0].distance_leaf(p);
+            let (b, b_leaf) = self.children[1].distance_leaf(p);
+            let (d, second) = op.combine(a, b);
+            if second {
+                (d, self.children[0].leaf_count() + b_leaf)
             } else {
-                return Err("Stack is empty. Expected number for \"height\" of Box.".into());
+                (d, a_leaf)
             }
-            if let Some(v) = stack.pop() {
-                if let Some(n) = v.to_number() {
-                    self.size.x = n;
-                } else {
-                    return Err("Wrong value on stack. Expected number for \"width\" of Box.".into());
-                }
+        } else {
+            let primitive = self.sdf_type.primitive();
+            if self.params.len() == primitive.params.len() {
+                ((primitive.distance)(p, &self.params), 0)
             } else {
-                return Err("Stack is empty. Expected number for \"width\" of Box.".into());
+                (f32::MAX, 0)
             }
-        } else
-        if self.sdf_type == Sphere {
+        }
+    }
+
+    /// Read the properties of the SDF from the stack.
+    pub fn read_properties(&mut self, stack:  &mut Vec<Value>) -> Result<(), RpuError> {
+
+        if self.op.is_some() {
+            return Ok(());
+        }
+
+        let primitive = self.sdf_type.primitive();
+        let mut params = vec![0.0; primitive.params.len()];
+
+        for (index, param) in primitive.params.iter().enumerate().rev() {
             if let Some(v) = stack.pop() {
                 if let Some(n) = v.to_number() {
-                    self.radius = n;
+                    params[index] = n;
                 } else {
-                    return Err("Wrong value on stack. Expected number for \"radius\" of Sphere.".into());
+                    return Err(RpuError::type_mismatch("number", &v, &format!("\"{}\" of {}", param, primitive.name)));
                 }
             } else {
-                return Err("Stack is empty. Expected number for \"radius\" of Sphere.".into());
+                return Err(RpuError::stack_underflow("number", &format!("\"{}\" of {}", param, primitive.name)));
             }
         }
 
-        loop {
-            if let Some(option) = stack.last() {
-                match option {
-                    Value::Array(values) => {
-                        self.textures = values.clone();
-                        _ = stack.pop();
-                    },
-                    _ => {
-                        break;
-                    }
-                }
-            } else {
-                break;
+        if let Some((index, expected)) = (primitive.validate)(&params) {
+            return Err(RpuError::wrong_value(expected, &format!("\"{}\" of {}", primitive.params[index], primitive.name)));
+        }
+
+        self.params = params;
+
+        // At most one array of palette indices below the parameters holds the colors of the
+        // shape, other arrays are values of their own and stay on the stack
+        if let Some(Value::Array(values)) = stack.last() {
+            if !values.is_empty() && values.iter().all(|v| v.to_color().is_some()) {
+                self.textures = values.clone();
+                _ = stack.pop();
             }
         }
 
         Ok(())
     }
 
+    /// Picks a random color index for each primitive of the tree
+    pub fn get_colors(&self, rng: &mut ThreadRng) -> Vec<u8> {
+        if self.op.is_some() {
+            self.children.iter().flat_map(|c| c.get_colors(rng)).collect()
+        } else {
+            vec![self.get_color(rng)]
+        }
+    }
+
+    /// Set the material of all primitives of the shape
+    pub fn set_material(&mut self, material: u8) {
+        self.material = material;
+        for child in &mut self.children {
+            child.set_material(material);
+        }
+    }
+
+    /// The material of each primitive of the shape, in the order of the leaf indices
+    pub fn get_materials(&self) -> Vec<u8> {
+        if self.op.is_some() {
+            self.children.iter().flat_map(|c| c.get_materials()).collect()
+        } else {
+            vec![self.material]
+        }
+    }
+
     /// Gets a random color index
     pub fn get_color(&self, rng: &mut ThreadRng) -> u8 {
         if self.textures.is_empty() == false {
@@ -132,48 +681,37 @@ impl SDF3D {
         }
     }
 
-    /// Generates a bounding box centered at 0, 0, 0
+    /// Generates the bounding box in the local space of the shape, primitives are centered at 0, 0, 0
     pub fn create_local_bbox(&self) -> AABB {
-        let mut min: Vec3<f32> = Vec3f::zero();
-        let mut max: Vec3<f32> = Vec3f::zero();
+        let mut bbox = if let Some(op) = &self.op {
+            op.combine_bbox(self.children[0].create_local_bbox(), self.children[1].create_local_bbox())
+        } else {
+            let primitive = self.sdf_type.primitive();
+            if self.params.len() == primitive.params.len() {
+                (primitive.bbox)(&self.params)
+            } else {
+                AABB::centered(Vec3f::zero())
+            }
+        };
 
-        if self.sdf_type == Box {
-            min = Vec3f::zero() - self.size / 2.0;
-            max = Vec3f::zero() + self.size / 2.0;
-        } else
-        if self.sdf_type == Sphere {
-            min = Vec3f::zero() - self.radius;
-            max = Vec3f::zero() + self.radius;
+        for transform in &self.transforms {
+            bbox = transform.apply_bbox(bbox);
         }
 
-        AABB { min, max }
+        bbox
     }
 
-    /// Generates a bounding box centered at 0, 0, 0
+    /// Generates a bounding box at the given position
     pub fn create_bbox(&self, position: Vec3f) -> AABB {
-        let mut min: Vec3<f32> = Vec3f::zero();
-        let mut max: Vec3<f32> = Vec3f::zero();
-
-        if self.sdf_type == Box {
-            min = position - self.size / 2.0;
-            max = position + self.size / 2.0;
-        } else
-        if self.sdf_type == Sphere {
-            min = position - self.radius;
-            max = position + self.radius;
-        }
-
-        AABB { min, max }
+        let local = self.create_local_bbox();
+        AABB { min: local.min + position, max: local.max + position }
     }
 
     pub fn to_string(&self) -> String {
-        match self.sdf_type {
-            Sphere => {
-                "Sphere".into()
-            },
-            Box => {
-                "Box".into()
-            }
+        if let Some(op) = &self.op {
+            format!("{}({}, {})", op.name(), self.children[0].to_string(), self.children[1].to_string())
+        } else {
+            self.sdf_type.primitive().name.into()
         }
     }
 
@@ -263,3 +801,137 @@ impl SDF3D {
 
     }*/
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::evaluate;
+
+    /// The distance of the point to the primitive with the given parameters
+    fn distance(sdf_type: SDF3DType, params: &[f32], p: Vec3f) -> f32 {
+        (sdf_type.primitive().distance)(p, params)
+    }
+
+    #[test]
+    fn primitive_distances() {
+        use SDF3DType::*;
+        let cases : [(SDF3DType, &[f32], Vec3f, f32); 17] = [
+            (Box, &[1.0, 2.0, 3.0], Vec3f::zero(), -0.5),
+            (Box, &[1.0, 2.0, 3.0], vec3f(1.0, 0.0, 0.0), 0.5),
+            (Sphere, &[0.5], vec3f(0.0, 0.0, 2.0), 1.5),
+            (Cylinder, &[0.5, 2.0], vec3f(0.0, 2.0, 0.0), 1.0),
+            (Cylinder, &[0.5, 2.0], vec3f(1.0, 0.0, 0.0), 0.5),
+            (Capsule, &[0.5, 2.0], vec3f(0.0, 2.0, 0.0), 1.0),
+            (Capsule, &[0.5, 2.0], vec3f(1.0, 0.5, 0.0), 0.5),
+            (Torus, &[1.0, 0.25], vec3f(1.0, 0.0, 0.0), -0.25),
+            (Torus, &[1.0, 0.25], Vec3f::zero(), 0.75),
+            (Cone, &[1.0, 2.0], vec3f(0.0, 2.0, 0.0), 1.0),
+            (Cone, &[1.0, 2.0], vec3f(0.0, -2.0, 0.0), 1.0),
+            (HexPrism, &[1.0, 2.0], vec3f(0.0, 2.0, 0.0), 1.0),
+            (HexPrism, &[1.0, 2.0], vec3f(0.0, 0.0, 2.0), 1.0),
+            (RoundBox, &[2.0, 2.0, 2.0, 0.5], vec3f(2.0, 0.0, 0.0), 1.0),
+            (Ellipsoid, &[2.0, 4.0, 6.0], vec3f(0.0, 2.0, 0.0), 0.0),
+            (Plane, &[0.0, 2.0, 0.0, 0.5], vec3f(3.0, 2.0, -1.0), 1.5),
+            (Plane, &[0.0, 0.0, 0.0, 0.5], Vec3f::zero(), f32::MAX),
+        ];
+        for (sdf_type, params, p, expected) in cases {
+            let d = distance(sdf_type, params, p);
+            assert!((d - expected).abs() < 1e-5, "{:?} {:?}: {} instead of {}", sdf_type, p, d, expected);
+        }
+    }
+
+    #[test]
+    fn primitive_bboxes_contain_the_shape() {
+        let params : [&[f32]; 10] = [&[1.0, 2.0, 3.0], &[0.5], &[0.5, 2.0], &[0.5, 2.0], &[1.0, 0.25], &[1.0, 2.0], &[1.0, 2.0], &[2.0, 1.0, 2.0, 0.3], &[2.0, 1.0, 3.0], &[0.0, 1.0, 0.0, 0.5]];
+
+        for (primitive, params) in PRIMITIVES.iter().zip(params) {
+            assert_eq!(primitive.params.len(), params.len(), "{}", primitive.name);
+            assert_eq!((primitive.validate)(params), None, "{}", primitive.name);
+            let bbox = (primitive.bbox)(params);
+
+            let mut inside = 0;
+            for i in 0..25 * 25 * 25 {
+                let p = (vec3f((i % 25) as f32, (i / 25 % 25) as f32, (i / 625) as f32) - 12.0) / 6.0;
+                if (primitive.distance)(p, params) < 0.0 {
+                    inside += 1;
+                    assert!((0..3).all(|a| p[a] >= bbox.min[a] - 1e-4 && p[a] <= bbox.max[a] + 1e-4), "{} {:?} {:?}", primitive.name, p, bbox);
+                }
+            }
+            assert!(inside > 0, "{}", primitive.name);
+        }
+
+        // Only axis aligned planes are bounded, on the side of their normal
+        let bbox = (SDF3DType::Plane.primitive().bbox)(&[0.0, 1.0, 0.0, 0.5]);
+        assert_eq!((bbox.max.y, bbox.min.y, bbox.max.x), (0.5, f32::NEG_INFINITY, f32::INFINITY));
+        assert!(!(SDF3DType::Plane.primitive().bbox)(&[1.0, 1.0, 0.0, 0.0]).is_finite());
+    }
+
+    #[test]
+    fn primitives_reject_invalid_parameters() {
+        let wrong_value = |input: &str| match evaluate(input).err().map(|err| err.kind) {
+            Some(ErrorKind::WrongValue { expected, target }) => (expected, target),
+            kind => panic!("{}: {:?}", input, kind),
+        };
+        let expect = |expected: &str, target: &str| (expected.to_string(), target.to_string());
+
+        assert_eq!(wrong_value("-0.3 SPHERE BAKE"), expect("positive number", "\"radius\" of Sphere"));
+        assert_eq!(wrong_value("1 0 1 BOX"), expect("positive number", "\"height\" of Box"));
+        assert_eq!(wrong_value("0.5 -1 CYLINDER"), expect("positive number", "\"height\" of Cylinder"));
+        assert_eq!(wrong_value("1 0 TORUS"), expect("positive number", "\"thickness\" of Torus"));
+        assert_eq!(wrong_value("1 0.5 1 0.3 ROUND-BOX"), expect("number from 0 to half of the smallest side", "\"radius\" of RoundBox"));
+        assert_eq!(wrong_value("1 1 1 -0.1 ROUND-BOX"), expect("number from 0 to half of the smallest side", "\"radius\" of RoundBox"));
+        assert_eq!(wrong_value("0 0 0 1 PLANE"), expect("normal which is not zero", "\"normal x\" of Plane"));
+
+        assert!(evaluate("1 1 1 0 ROUND-BOX 0 -1 0 -2 PLANE UNION BAKE").is_ok());
+    }
+
+    #[test]
+    fn hard_operations() {
+        assert_eq!(CSGOp::Union.combine(1.0, 2.0), (1.0, false));
+        assert_eq!(CSGOp::Union.combine(2.0, -1.0), (-1.0, true));
+        assert_eq!(CSGOp::Difference.combine(-1.0, 2.0), (-1.0, false));
+        assert_eq!(CSGOp::Difference.combine(-1.0, -0.5), (0.5, true));
+        assert_eq!(CSGOp::Intersect.combine(-1.0, -2.0), (-1.0, false));
+        assert_eq!(CSGOp::Intersect.combine(-1.0, 0.5), (0.5, true));
+    }
+
+    #[test]
+    fn smooth_operations() {
+        let k = 0.4;
+        let (union, _) = CSGOp::SmoothUnion(k).combine(0.1, 0.1);
+        assert!((union - (0.1 - k / 4.0)).abs() < 1e-6);
+        let (intersect, _) = CSGOp::SmoothIntersect(k).combine(0.1, 0.1);
+        assert!((intersect - (0.1 + k / 4.0)).abs() < 1e-6);
+        let (difference, _) = CSGOp::SmoothDifference(k).combine(0.1, -0.1);
+        assert!((difference - (0.1 + k / 4.0)).abs() < 1e-6);
+
+        for (a, b) in [(0.0, 0.1), (-0.3, 0.2), (0.5, -0.05), (1.0, 2.0)] {
+            assert!(CSGOp::SmoothUnion(k).combine(a, b).0 <= CSGOp::Union.combine(a, b).0);
+            assert!(CSGOp::SmoothIntersect(k).combine(a, b).0 >= CSGOp::Intersect.combine(a, b).0);
+            assert!(CSGOp::SmoothDifference(k).combine(a, b).0 >= CSGOp::Difference.combine(a, b).0);
+        }
+
+        // Distances further apart than the radius and radii of zero blend nothing
+        for (smooth, hard) in [(CSGOp::SmoothUnion(k), CSGOp::Union), (CSGOp::SmoothDifference(k), CSGOp::Difference), (CSGOp::SmoothIntersect(k), CSGOp::Intersect)] {
+            assert_eq!(smooth.combine(1.0, -2.0), hard.combine(1.0, -2.0));
+            assert_eq!(smooth.with_radius(0.0).combine(0.1, 0.1), hard.combine(0.1, 0.1));
+            assert_eq!(smooth.with_radius(-1.0).combine(0.1, -0.2), hard.combine(0.1, -0.2));
+        }
+    }
+
+    #[test]
+    fn operation_words_and_bboxes() {
+        assert_eq!(CSGOp::from_word("SUBTRACT"), Some(CSGOp::Difference));
+        assert_eq!(CSGOp::from_word("SMOOTH-UNION").map(|op| op.with_radius(0.5)), Some(CSGOp::SmoothUnion(0.5)));
+        assert_eq!(CSGOp::Union.with_radius(0.5), CSGOp::Union);
+        assert!(CSGOp::SmoothIntersect(0.0).is_smooth() && !CSGOp::Intersect.is_smooth());
+        assert_eq!(CSGOp::from_word("MERGE"), None);
+
+        let a = AABB::centered(vec3f(1.0, 1.0, 1.0));
+        let b = AABB { min: vec3f(0.0, 0.0, 0.0), max: vec3f(3.0, 3.0, 3.0) };
+        assert_eq!(CSGOp::Union.combine_bbox(a.clone(), b.clone()), AABB { min: vec3f(-1.0, -1.0, -1.0), max: vec3f(3.0, 3.0, 3.0) });
+        assert_eq!(CSGOp::SmoothUnion(0.4).combine_bbox(a.clone(), b.clone()), AABB { min: vec3f(-1.1, -1.1, -1.1), max: vec3f(3.1, 3.1, 3.1) });
+        assert_eq!(CSGOp::SmoothDifference(0.4).combine_bbox(a.clone(), b.clone()), a);
+        assert_eq!(CSGOp::Intersect.combine_bbox(a, b), AABB { min: vec3f(0.0, 0.0, 0.0), max: vec3f(1.0, 1.0, 1.0) });
+    }
+}
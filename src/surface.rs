@@ -0,0 +1,497 @@
+use crate::prelude::*;
+use crate::format::Writer;
+
+use std::fmt::Write as _;
+use rayon::iter::IntoParallelIterator;
+
+/// The maximum number of grid cells per axis, which keeps the sampled distances of a grid
+/// below about 70 MB
+const MAX_CELLS: usize = 256;
+
+/// The corner offsets of a grid cell, corner i has the offset (i & 1, (i >> 1) & 1, (i >> 2) & 1)
+fn corner(i: usize) -> [usize; 3] {
+    [i & 1, (i >> 1) & 1, (i >> 2) & 1]
+}
+
+/// The 12 edges of a cell as pairs of corners
+const EDGES: [[usize; 2]; 12] = [
+    [0, 1], [2, 3], [4, 5], [6, 7],
+    [0, 2], [1, 3], [4, 6], [5, 7],
+    [0, 4], [1, 5], [2, 6], [3, 7],
+];
+
+/// The 6 faces of a cell, corners in cyclic order
+const FACES: [[usize; 4]; 6] = [
+    [0, 1, 3, 2], [4, 5, 7, 6],
+    [0, 1, 5, 4], [2, 3, 7, 6],
+    [0, 2, 6, 4], [1, 3, 7, 5],
+];
+
+fn edge_of(a: usize, b: usize) -> usize {
+    EDGES.iter().position(|e| (e[0] == a && e[1] == b) || (e[0] == b && e[1] == a)).unwrap()
+}
+
+fn corner_position(i: usize) -> Vec3f {
+    let o = corner(i);
+    vec3f(o[0] as f32, o[1] as f32, o[2] as f32)
+}
+
+fn edge_midpoint(e: usize) -> Vec3f {
+    (corner_position(EDGES[e][0]) + corner_position(EDGES[e][1])) * 0.5
+}
+
+/// Create the marching cubes table: for every configuration of inside corners, the closed
+/// loops of crossed edges which make up the surface inside the cell, wound counter clockwise
+/// when seen from outside the shape. The loops are found by connecting the crossings on each
+/// face, ambiguous faces always separate the inside corners, which keeps neighbouring cells
+/// consistent.
+fn marching_cubes_table() -> Vec<Vec<Vec<usize>>> {
+    // Order the corners of every face counter clockwise when seen from outside the cell
+    let faces : Vec<[usize; 4]> = FACES.iter().map(|face| {
+        let p : Vec<Vec3f> = face.iter().map(|c| corner_position(*c)).collect();
+        let outward = (p[0] + p[1] + p[2] + p[3]) * 0.25 - vec3f(0.5, 0.5, 0.5);
+        if dot(cross(p[1] - p[0], p[2] - p[0]), outward) > 0.0 {
+            *face
+        } else {
+            [face[3], face[2], face[1], face[0]]
+        }
+    }).collect();
+
+    let mut table = vec![];
+
+    for config in 0..256_usize {
+        let inside = |c: usize| config & (1 << c) != 0;
+
+        // The directed segments on all faces, from the edge where the face boundary enters
+        // the inside to the edge where it leaves it. Each crossed edge is entered on one of
+        // its faces and left on the other.
+        let mut segments : Vec<[usize; 2]> = vec![];
+        for face in &faces {
+            let edge = |k: usize| edge_of(face[k % 4], face[(k + 1) % 4]);
+            let enters : Vec<usize> = (0..4).filter(|k| !inside(face[*k]) && inside(face[(k + 1) % 4])).collect();
+
+            for k in enters {
+                // Follow the inside corners to the edge where the boundary leaves again
+                let mut j = k + 1;
+                while inside(face[(j + 1) % 4]) {
+                    j += 1;
+                }
+                segments.push([edge(k), edge(j)]);
+            }
+        }
+
+        // Chain the segments into loops
+        let mut loops = vec![];
+        while let Some(first) = segments.pop() {
+            let mut chain = vec![first[0]];
+            let mut next = first[1];
+            while next != chain[0] {
+                chain.push(next);
+                let index = segments.iter().position(|s| s[0] == next).unwrap();
+                next = segments.remove(index)[1];
+            }
+            loops.push(chain);
+        }
+        table.push(loops);
+    }
+
+    // Make the loops face away from the inside corners
+    let first = &table[1][0];
+    let normal = cross(edge_midpoint(first[1]) - edge_midpoint(first[0]), edge_midpoint(first[2]) - edge_midpoint(first[0]));
+    if dot(normal, vec3f(1.0, 1.0, 1.0)) < 0.0 {
+        for loops in &mut table {
+            for lp in loops {
+                lp.reverse();
+            }
+        }
+    }
+
+    table
+}
+
+/// A triangle mesh
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct TriangleMesh {
+    pub vertices            : Vec<Vec3f>,
+    pub triangles           : Vec<[u32; 3]>,
+}
+
+impl TriangleMesh {
+    pub fn new() -> Self {
+        Self {
+            vertices        : vec![],
+            triangles       : vec![],
+        }
+    }
+
+    /// The normal of the given triangle
+    pub fn normal(&self, triangle: usize) -> Vec3f {
+        let [a, b, c] = self.triangles[triangle];
+        let a = self.vertices[a as usize];
+        let n = cross(self.vertices[b as usize] - a, self.vertices[c as usize] - a);
+        let l = length(n);
+        if l > 0.0 { n / l } else { n }
+    }
+
+    /// Write the mesh to the given file, the format is taken from the extension: .obj or .stl
+    pub fn write(&self, path: &str) -> Result<(), RpuError> {
+        let io_error = |message: String| RpuError::new(ErrorKind::Io { path: path.into(), message });
+        let lower = path.to_lowercase();
+
+        if lower.ends_with(".obj") {
+            std::fs::write(path, self.to_obj()).map_err(|err| io_error(err.to_string()))
+        } else
+        if lower.ends_with(".stl") {
+            std::fs::write(path, self.to_stl()).map_err(|err| io_error(err.to_string()))
+        } else {
+            Err(io_error("Unsupported surface format, use .obj or .stl.".into()))
+        }
+    }
+
+    /// Wavefront OBJ
+    pub fn to_obj(&self) -> String {
+        let mut obj = String::new();
+        _ = writeln!(obj, "# Generated by rpu-lang.org");
+        for v in &self.vertices {
+            _ = writeln!(obj, "v {} {} {}", v.x, v.y, v.z);
+        }
+        for t in &self.triangles {
+            _ = writeln!(obj, "f {} {} {}", t[0] + 1, t[1] + 1, t[2] + 1);
+        }
+        obj
+    }
+
+    /// Binary STL
+    pub fn to_stl(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        let mut header = b"Generated by rpu-lang.org".to_vec();
+        header.resize(80, 0);
+        w.data.extend(header);
+        w.u32(self.triangles.len() as u32);
+        for (index, t) in self.triangles.iter().enumerate() {
+            w.vec3(self.normal(index));
+            for v in t {
+                w.vec3(self.vertices[*v as usize]);
+            }
+            w.data.extend_from_slice(&[0, 0]);
+        }
+        w.data
+    }
+}
+
+/// The distances of a shape sampled on a regular grid
+struct Grid {
+    origin              : Vec3f,
+    cell                : f32,
+    /// Number of samples per axis
+    size                : [usize; 3],
+    distances           : Vec<f32>,
+}
+
+impl Grid {
+    /// Sample the shape with the given cell size, the grid covers the bounding box of the
+    /// shape with its infinite sides bounded by limit, plus a margin of one cell.
+    fn new(sdf: &SDF3D, mut cell: f32, limit: &AABB) -> Self {
+        let bbox = sdf.create_local_bbox().bound(limit);
+        let extent = bbox.get_size();
+        let largest = max(extent.x, max(extent.y, extent.z));
+        if largest / cell > MAX_CELLS as f32 {
+            cell = largest / MAX_CELLS as f32;
+        }
+
+        let origin = bbox.min - cell;
+        let size = [
+            (extent.x / cell).ceil() as usize + 3,
+            (extent.y / cell).ceil() as usize + 3,
+            (extent.z / cell).ceil() as usize + 3];
+
+        let distances : Vec<f32> = (0..size[0] * size[1] * size[2]).into_par_iter().map(|index| {
+            let x = index % size[0];
+            let y = (index / size[0]) % size[1];
+            let z = index / (size[0] * size[1]);
+            sdf.distance_leaf(origin + vec3f(x as f32, y as f32, z as f32) * cell).0
+        }).collect();
+
+        Self {
+            origin,
+            cell,
+            size,
+            distances,
+        }
+    }
+
+    fn index(&self, p: [usize; 3]) -> usize {
+        p[0] + p[1] * self.size[0] + p[2] * self.size[0] * self.size[1]
+    }
+
+    fn at(&self, p: [usize; 3]) -> f32 {
+        self.distances[self.index(p)]
+    }
+
+    fn position(&self, p: [usize; 3]) -> Vec3f {
+        self.origin + vec3f(p[0] as f32, p[1] as f32, p[2] as f32) * self.cell
+    }
+
+    /// The point where the surface crosses the edge between two neighbouring samples
+    fn crossing(&self, a: [usize; 3], b: [usize; 3]) -> Vec3f {
+        let da = self.at(a);
+        let db = self.at(b);
+        let t = if da != db { clamp(da / (da - db), 0.0, 1.0) } else { 0.5 };
+        self.position(a) + (self.position(b) - self.position(a)) * t
+    }
+}
+
+/// The normalized gradient of the shape
+fn gradient(sdf: &SDF3D, p: Vec3f, h: f32) -> Vec3f {
+    let d = |o: Vec3f| sdf.distance_leaf(p + o).0;
+    let g = vec3f(
+        d(vec3f(h, 0.0, 0.0)) - d(vec3f(-h, 0.0, 0.0)),
+        d(vec3f(0.0, h, 0.0)) - d(vec3f(0.0, -h, 0.0)),
+        d(vec3f(0.0, 0.0, h)) - d(vec3f(0.0, 0.0, -h)));
+    let l = length(g);
+    if l > 0.0 { g / l } else { g }
+}
+
+/// Solve the 3x3 system with Cramer's rule
+fn solve3(m: [[f32; 3]; 3], b: [f32; 3]) -> Option<[f32; 3]> {
+    let det = |m: [[f32; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+        m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+        m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(m);
+    if d.abs() < 1e-12 {
+        return None;
+    }
+    let mut x = [0.0; 3];
+    for (col, x) in x.iter_mut().enumerate() {
+        let mut mc = m;
+        for row in 0..3 {
+            mc[row][col] = b[row];
+        }
+        *x = det(mc) / d;
+    }
+    Some(x)
+}
+
+impl SDF3D {
+
+    /// Extract the surface of the shape with marching cubes. The cell size is in world units.
+    pub fn marching_cubes(&self, cell: f32, limit: &AABB) -> TriangleMesh {
+        let grid = Grid::new(self, cell, limit);
+        let table = marching_cubes_table();
+        let mut mesh = TriangleMesh::new();
+
+        // Vertices are shared between cells, keyed by the sample and axis of their edge
+        let mut shared : FxHashMap<(usize, usize), u32> = FxHashMap::default();
+
+        for z in 0..grid.size[2] - 1 {
+            for y in 0..grid.size[1] - 1 {
+                for x in 0..grid.size[0] - 1 {
+                    let corners : Vec<[usize; 3]> = (0..8).map(|i| {
+                        let o = corner(i);
+                        [x + o[0], y + o[1], z + o[2]]
+                    }).collect();
+
+                    let mut config = 0;
+                    for (i, c) in corners.iter().enumerate() {
+                        if grid.at(*c) < 0.0 {
+                            config |= 1 << i;
+                        }
+                    }
+
+                    for lp in &table[config] {
+                        let ids : Vec<u32> = lp.iter().map(|e| {
+                            let (a, b) = (corners[EDGES[*e][0]], corners[EDGES[*e][1]]);
+                            let axis = (0..3).find(|k| a[*k] != b[*k]).unwrap();
+                            *shared.entry((grid.index(a), axis)).or_insert_with(|| {
+                                mesh.vertices.push(grid.crossing(a, b));
+                                (mesh.vertices.len() - 1) as u32
+                            })
+                        }).collect();
+
+                        for k in 1..ids.len() - 1 {
+                            mesh.triangles.push([ids[0], ids[k], ids[k + 1]]);
+                        }
+                    }
+                }
+            }
+        }
+
+        mesh
+    }
+
+    /// Extract the surface of the shape with dual contouring, which keeps sharp edges and corners.
+    /// The vertex of each cell minimizes the distance to the tangent planes at its edge crossings,
+    /// biased towards their mass point.
+    pub fn dual_contouring(&self, cell: f32, limit: &AABB) -> TriangleMesh {
+        let grid = Grid::new(self, cell, limit);
+        let mut mesh = TriangleMesh::new();
+
+        let mut cells : FxHashMap<(usize, usize, usize), u32> = FxHashMap::default();
+
+        for z in 0..grid.size[2] - 1 {
+            for y in 0..grid.size[1] - 1 {
+                for x in 0..grid.size[0] - 1 {
+                    let corners : Vec<[usize; 3]> = (0..8).map(|i| {
+                        let o = corner(i);
+                        [x + o[0], y + o[1], z + o[2]]
+                    }).collect();
+
+                    let mut points = vec![];
+                    for e in &EDGES {
+                        let (a, b) = (corners[e[0]], corners[e[1]]);
+                        if (grid.at(a) < 0.0) != (grid.at(b) < 0.0) {
+                            points.push(grid.crossing(a, b));
+                        }
+                    }
+                    if points.is_empty() {
+                        continue;
+                    }
+
+                    let mass = points.iter().fold(Vec3f::zero(), |s, p| s + *p) / points.len() as f32;
+
+                    // Least squares of the tangent planes, regularized towards the mass point
+                    let lambda = 0.05;
+                    let mut ata = [[0.0_f32; 3]; 3];
+                    let mut atb = [0.0_f32; 3];
+                    for i in 0..3 {
+                        ata[i][i] = lambda;
+                        atb[i] = lambda * mass[i];
+                    }
+                    for p in &points {
+                        let n = gradient(self, *p, grid.cell * 0.1);
+                        let d = dot(n, *p);
+                        for i in 0..3 {
+                            for j in 0..3 {
+                                ata[i][j] += n[i] * n[j];
+                            }
+                            atb[i] += n[i] * d;
+                        }
+                    }
+
+                    let min = grid.position(corners[0]);
+                    let max = grid.position(corners[7]);
+                    let mut vertex = mass;
+                    if let Some(v) = solve3(ata, atb) {
+                        let v = vec3f(v[0], v[1], v[2]);
+                        if v.x >= min.x && v.y >= min.y && v.z >= min.z && v.x <= max.x && v.y <= max.y && v.z <= max.z {
+                            vertex = v;
+                        }
+                    }
+
+                    mesh.vertices.push(vertex);
+                    cells.insert((x, y, z), (mesh.vertices.len() - 1) as u32);
+                }
+            }
+        }
+
+        // One quad between the four cells around every crossed edge
+        for z in 1..grid.size[2] - 1 {
+            for y in 1..grid.size[1] - 1 {
+                for x in 1..grid.size[0] - 1 {
+                    let p = [x, y, z];
+                    for a in 0..3 {
+                        let mut q = p;
+                        q[a] += 1;
+                        if q[a] >= grid.size[a] {
+                            continue;
+                        }
+                        let inside = grid.at(p) < 0.0;
+                        if inside == (grid.at(q) < 0.0) {
+                            continue;
+                        }
+
+                        let u = (a + 1) % 3;
+                        let v = (a + 2) % 3;
+                        let around = [(1, 1), (0, 1), (0, 0), (1, 0)].map(|(du, dv)| {
+                            let mut c = p;
+                            c[u] -= du;
+                            c[v] -= dv;
+                            cells.get(&(c[0], c[1], c[2])).cloned()
+                        });
+
+                        if let [Some(c0), Some(c1), Some(c2), Some(c3)] = around {
+                            // The surface normal points along the edge if the edge starts inside
+                            if inside {
+                                mesh.triangles.push([c0, c1, c2]);
+                                mesh.triangles.push([c0, c2, c3]);
+                            } else {
+                                mesh.triangles.push([c0, c2, c1]);
+                                mesh.triangles.push([c0, c3, c2]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::baked;
+    use super::{Grid, MAX_CELLS};
+
+    const CELL : f32 = 0.05;
+
+    /// A sphere with a radius of 0.3
+    fn sphere() -> SDF3D {
+        match baked("0.3 SPHERE").stack.pop() {
+            Some(Value::Shape3D(sdf)) => sdf,
+            _ => panic!("No shape on the stack."),
+        }
+    }
+
+    /// The largest distance of a vertex to the surface of the shape
+    fn largest_error(mesh: &TriangleMesh, sdf: &SDF3D) -> f32 {
+        mesh.vertices.iter().map(|v| sdf.distance(*v, Vec3f::zero()).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn marching_cubes_lies_on_the_surface() {
+        let sdf = sphere();
+        let mesh = sdf.marching_cubes(CELL, &Bake::new().limit);
+
+        assert!(mesh.triangles.len() > 100);
+        let error = largest_error(&mesh, &sdf);
+        assert!(error < 0.1 * CELL, "{}", error);
+    }
+
+    #[test]
+    fn dual_contouring_lies_on_the_surface() {
+        let sdf = sphere();
+        let mesh = sdf.dual_contouring(CELL, &Bake::new().limit);
+
+        assert!(mesh.triangles.len() > 100);
+        let error = largest_error(&mesh, &sdf);
+        assert!(error < 0.1 * CELL, "{}", error);
+    }
+
+    #[test]
+    fn grid_cells_are_limited() {
+        let sdf = match baked("4 1 1 BOX").stack.pop() {
+            Some(Value::Shape3D(sdf)) => sdf,
+            _ => panic!("No shape on the stack."),
+        };
+        let grid = Grid::new(&sdf, 0.001, &Bake::new().limit);
+
+        assert!((grid.cell - 4.0 / MAX_CELLS as f32).abs() < 1e-6, "{}", grid.cell);
+        assert_eq!(grid.size[0], MAX_CELLS + 3);
+        assert!(grid.size[1] < MAX_CELLS && grid.size[2] < MAX_CELLS);
+        assert_eq!(grid.distances.len(), grid.size[0] * grid.size[1] * grid.size[2]);
+    }
+
+    #[test]
+    fn stl_layout() {
+        let mesh = sphere().marching_cubes(CELL, &Bake::new().limit);
+        let stl = mesh.to_stl();
+        let n = mesh.triangles.len();
+
+        assert_eq!(stl.len(), 80 + 4 + 50 * n);
+        assert_eq!(u32::from_le_bytes(stl[80..84].try_into().unwrap()) as usize, n);
+    }
+}
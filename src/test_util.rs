@@ -0,0 +1,43 @@
+//! Helpers shared by the unit tests.
+
+use crate::prelude::*;
+
+/// A gold sphere next to a box in the default material, each with its own color
+pub const TWO_MATERIALS: &str = "MATERIAL gold 0.2 1 0 ; [40] 0.2 SPHERE gold [180] 0.2 0.1 0.2 BOX 0.4 0 0 TRANSLATE UNION BAKE";
+
+/// Evaluate the input in a fresh interpreter
+pub fn evaluate(input: &str) -> Result<RPU, RpuError> {
+    let mut rpu = RPU::new();
+    let mut buffer = ColorBuffer::new(8, 8);
+    rpu.evaluate(input, &mut buffer, &mut vec![])?;
+    Ok(rpu)
+}
+
+/// A fresh interpreter after evaluating the input, which has to succeed
+pub fn baked(input: &str) -> RPU {
+    match evaluate(input) {
+        Ok(rpu) => rpu,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+/// A voxel with its tile, position in the tile, color and material
+pub type Voxel = ((i32, i32, i32), usize, usize, usize, (u8, u8));
+
+/// All voxels of the world, sorted
+pub fn tile_voxels(world: &World) -> Vec<Voxel> {
+    let mut voxels = vec![];
+    for (key, tile) in &world.map.tiles {
+        for z in 0..tile.size {
+            for y in 0..tile.size {
+                for x in 0..tile.size {
+                    if let Some(v) = tile.get_voxel(x, y, z) {
+                        voxels.push((*key, x, y, z, v));
+                    }
+                }
+            }
+        }
+    }
+    voxels.sort();
+    voxels
+}
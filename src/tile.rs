@@ -1,17 +1,53 @@
 use crate::prelude::*;
 
+/// The edge length of a brick in voxels
+pub const BRICK_SIZE: usize = 8;
+
+/// A voxel, holding the color and material index
+pub type Voxel = Option<(u8, u8)>;
+
+/// A brick of BRICK_SIZE^3 voxels
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum Brick {
+    /// All voxels of the brick have the same value
+    Uniform(Voxel),
+    Voxels(Vec<Voxel>),
+}
+
+/// The available storage backends
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum StorageKind {
+    Dense,
+    #[default]
+    Bricks,
+}
+
+/// The voxels of a tile
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum Voxels {
+    /// One entry per voxel
+    Dense(Vec<Voxel>),
+    /// A sparse brick map, bricks which are empty or hold only one value store a single entry
+    Bricks(Vec<Brick>),
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Tile {
     pub camera              : Camera,
     pub size                : usize,
 
-    pub data                : Vec<Option<(u8, u8)>>,
+    pub voxels              : Voxels,
 
     pub aabb                : Option<AABB>,
 }
 
 impl Tile {
     pub fn new(size: usize) -> Self {
+        Self::with_storage(size, StorageKind::Bricks)
+    }
+
+    /// Create a tile with the given storage backend
+    pub fn with_storage(size: usize, storage: StorageKind) -> Self {
 
         let mut camera = Camera::new(vec3f(0.0, 5.0, 5.0), Vec3f::zero(), 70.0);
 
@@ -21,7 +57,7 @@ impl Tile {
         camera.origin = camera.center;
         camera.origin.z += 20.0;
 
-        let data = vec![None; size * size * size];
+        let voxels = Self::create_voxels(size, storage);
 
         // Left wall
         // for z in 0..size {
@@ -46,35 +82,63 @@ impl Tile {
         Self {
             camera          : camera,
 
-            data            : data,
+            voxels,
             size,
 
             aabb            : None,
         }
     }
 
+    /// Create empty voxel storage for the given size
+    fn create_voxels(size: usize, storage: StorageKind) -> Voxels {
+        match storage {
+            StorageKind::Dense => Voxels::Dense(vec![None; size * size * size]),
+            StorageKind::Bricks => {
+                let bricks = size.div_ceil(BRICK_SIZE);
+                Voxels::Bricks(vec![Brick::Uniform(None); bricks * bricks * bricks])
+            }
+        }
+    }
+
+    /// The storage backend of the tile
+    pub fn storage(&self) -> StorageKind {
+        match &self.voxels {
+            Voxels::Dense(_) => StorageKind::Dense,
+            Voxels::Bricks(_) => StorageKind::Bricks,
+        }
+    }
+
     /// Index for a given voxel
     fn index(&self, x: usize, y: usize, z: usize) -> usize {
         x + y * self.size + z * self.size * self.size
     }
 
+    /// Index of the brick and of the voxel inside the brick
+    fn brick_index(&self, x: usize, y: usize, z: usize) -> (usize, usize) {
+        let bricks = self.size.div_ceil(BRICK_SIZE);
+        let brick = x / BRICK_SIZE + (y / BRICK_SIZE) * bricks + (z / BRICK_SIZE) * bricks * bricks;
+        let voxel = x % BRICK_SIZE + (y % BRICK_SIZE) * BRICK_SIZE + (z % BRICK_SIZE) * BRICK_SIZE * BRICK_SIZE;
+        (brick, voxel)
+    }
+
     /// Checks if the given voxel exists
     pub fn exists(&self, x: usize, y: usize, z: usize) -> bool {
-        if x < self.size && y < self.size && z < self.size {
-            if self.data[self.index(x, y, z)].is_some() {
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        }
+        self.get_voxel(x, y, z).is_some()
     }
 
     /// Get a voxel
     pub fn get_voxel(&self, x: usize, y: usize, z: usize) -> Option<(u8, u8)> {
         if x < self.size && y < self.size && z < self.size {
-            self.data[self.index(x, y, z)]
+            match &self.voxels {
+                Voxels::Dense(data) => data[self.index(x, y, z)],
+                Voxels::Bricks(bricks) => {
+                    let (brick, voxel) = self.brick_index(x, y, z);
+                    match &bricks[brick] {
+                        Brick::Uniform(value) => *value,
+                        Brick::Voxels(data) => data[voxel],
+                    }
+                }
+            }
         } else {
             None
         }
@@ -84,13 +148,67 @@ impl Tile {
     pub fn set_voxel(&mut self, x: usize, y: usize, z: usize, voxel: Option<(u8, u8)>)  {
         if x < self.size && y < self.size && z < self.size {
             let index = self.index(x, y, z);
-            self.data[index] = voxel;
+            let (brick_index, voxel_index) = self.brick_index(x, y, z);
+            match &mut self.voxels {
+                Voxels::Dense(data) => data[index] = voxel,
+                Voxels::Bricks(bricks) => {
+                    let brick = &mut bricks[brick_index];
+                    if let Brick::Uniform(value) = brick {
+                        if *value == voxel {
+                            return;
+                        }
+                        *brick = Brick::Voxels(vec![*value; BRICK_SIZE * BRICK_SIZE * BRICK_SIZE]);
+                    }
+                    if let Brick::Voxels(data) = brick {
+                        data[voxel_index] = voxel;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collapse bricks whose voxels all have the same value
+    pub fn compress(&mut self) {
+        if let Voxels::Bricks(bricks) = &mut self.voxels {
+            for brick in bricks.iter_mut() {
+                if let Brick::Voxels(data) = brick {
+                    let first = data[0];
+                    if data.iter().all(|v| *v == first) {
+                        *brick = Brick::Uniform(first);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns true if the tile holds no voxels
+    pub fn is_empty(&self) -> bool {
+        match &self.voxels {
+            Voxels::Dense(data) => data.iter().all(|v| v.is_none()),
+            Voxels::Bricks(bricks) => bricks.iter().all(|b| match b {
+                Brick::Uniform(value) => value.is_none(),
+                Brick::Voxels(data) => data.iter().all(|v| v.is_none()),
+            }),
+        }
+    }
+
+    /// The approximate memory used by the voxels in bytes
+    pub fn memory_usage(&self) -> usize {
+        let voxel = std::mem::size_of::<Voxel>();
+        match &self.voxels {
+            Voxels::Dense(data) => data.capacity() * voxel,
+            Voxels::Bricks(bricks) => {
+                bricks.iter().map(|b| std::mem::size_of::<Brick>() + match b {
+                    Brick::Uniform(_) => 0,
+                    Brick::Voxels(data) => data.capacity() * voxel,
+                }).sum()
+            }
         }
     }
 
     /// Resize the content to the new size
     pub fn resize(&mut self, new_size: usize) {
-        let mut new_data = vec![None; new_size * new_size * new_size];
+        let mut resized = Tile::with_storage(new_size, self.storage());
 
         for z in 0..new_size {
             for y in 0..new_size {
@@ -99,16 +217,13 @@ impl Tile {
                     let old_y = y * self.size / new_size;
                     let old_z = z * self.size / new_size;
 
-                    let old_index = self.index(old_x, old_y, old_z);
-                    let new_index = x + y * new_size + z * new_size * new_size;
-
-                    new_data[new_index] = self.data[old_index];
+                    resized.set_voxel(x, y, z, self.get_voxel(old_x, old_y, old_z));
                 }
             }
         }
 
         self.size = new_size;
-        self.data = new_data;
+        self.voxels = resized.voxels;
     }
 
     /// Build an aaab for the tiles voxels
@@ -357,8 +472,65 @@ impl Tile {
 
     /// Set the voxel at the given position
     pub fn clear_all(&mut self) {
-        self.data = vec![None; self.size * self.size * self.size];
+        self.voxels = Self::create_voxels(self.size, self.storage());
         self.aabb = None;
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use super::{Brick, Voxels, BRICK_SIZE};
+
+    /// The voxel of a pattern which fills the first brick, clears the second and sets a few
+    /// voxels in the others
+    fn pattern(x: usize, y: usize, z: usize) -> Option<(u8, u8)> {
+        if x < BRICK_SIZE && y < BRICK_SIZE && z < BRICK_SIZE {
+            Some((1, 0))
+        } else
+        if (x * 7 + y * 3 + z * 5) % 11 == 0 && x >= 2 * BRICK_SIZE {
+            Some(((x + y + z) as u8, (x % 3) as u8))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn bricks_match_dense() {
+        let size = 20;
+        let mut dense = Tile::with_storage(size, StorageKind::Dense);
+        let mut bricks = Tile::with_storage(size, StorageKind::Bricks);
+
+        for tile in [&mut dense, &mut bricks] {
+            for z in 0..size {
+                for y in 0..size {
+                    for x in 0..size {
+                        // Set every voxel of the second brick first, then clear it again
+                        if (BRICK_SIZE..2 * BRICK_SIZE).contains(&x) && y < BRICK_SIZE && z < BRICK_SIZE {
+                            tile.set_voxel(x, y, z, Some((2, 0)));
+                        }
+                        tile.set_voxel(x, y, z, pattern(x, y, z));
+                    }
+                }
+            }
+        }
+
+        let same = |a: &Tile, b: &Tile| (0..size * size * size).all(|i| a.get_voxel(i % size, i / size % size, i / size / size) == b.get_voxel(i % size, i / size % size, i / size / size));
+        assert!(same(&dense, &bricks));
+
+        bricks.compress();
+        assert!(same(&dense, &bricks));
+        assert!(!bricks.is_empty());
+
+        // The full and the emptied bricks are collapsed
+        if let Voxels::Bricks(b) = &bricks.voxels {
+            assert_eq!(b[0], Brick::Uniform(Some((1, 0))));
+            assert_eq!(b[1], Brick::Uniform(None));
+            assert!(b.iter().any(|brick| matches!(brick, Brick::Voxels(_))));
+        } else {
+            panic!("The tile has no bricks.");
+        }
+        assert!(bricks.memory_usage() < dense.memory_usage());
+    }
+}
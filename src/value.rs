@@ -1,37 +1,109 @@
 use crate::prelude::*;
+use std::string::String;
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub enum Value {
     WordDefinitionStart(),
     WordDefinitionEnd(),
     Number(f32),
+    Int(i64),
+    Bool(bool),
+    Vec3(Vec3f),
     Shape3D(SDF3D),
     Array(Vec<Value>),
+    /// A block of code between braces, executed by words like MAP and EACH
+    Quotation(Vec<Value>),
     Command(String),
-    Config(String)
+    Config(String),
+    Word(String),
+    String(String),
 }
 
-pub use Value::*;
+use Value::*;
 
 impl Value {
 
+    /// The name of the type of the value, used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            WordDefinitionStart() | WordDefinitionEnd() => "definition",
+            Number(_) => "number",
+            Int(_) => "integer",
+            Bool(_) => "bool",
+            Vec3(_) => "vec3",
+            Shape3D(_) => "shape",
+            Array(_) => "array",
+            Quotation(_) => "quotation",
+            Command(_) | Config(_) | Word(_) => "word",
+            String(_) => "string",
+        }
+    }
+
     /// Converts the value to a number
     pub fn to_number(&self) -> Option<f32> {
         match self {
             Number(v) => {
                 Some(*v)
             },
+            Int(v) => {
+                Some(*v as f32)
+            },
+            _ => {
+                None
+            }
+        }
+    }
+
+    /// Converts the value to an integer, numbers are only converted without a fraction
+    pub fn to_int(&self) -> Option<i64> {
+        match self {
+            Int(v) => {
+                Some(*v)
+            },
+            Number(v) if v.fract() == 0.0 => {
+                Some(*v as i64)
+            },
+            _ => {
+                None
+            }
+        }
+    }
+
+    /// Converts the value to a flag, every non-zero number is true
+    pub fn to_bool(&self) -> Option<bool> {
+        match self {
+            Bool(v) => {
+                Some(*v)
+            },
+            Number(_) | Int(_) => {
+                self.to_number().map(|v| v != 0.0)
+            },
+            _ => {
+                None
+            }
+        }
+    }
+
+    /// Converts the value to a vector, arrays of three numbers are converted as well
+    pub fn to_vec3(&self) -> Option<Vec3f> {
+        match self {
+            Vec3(v) => {
+                Some(*v)
+            },
+            Array(values) if values.len() == 3 => {
+                Some(vec3f(values[0].to_number()?, values[1].to_number()?, values[2].to_number()?))
+            },
             _ => {
                 None
             }
         }
     }
 
-    /// Converts the value to a color index
+    /// Converts the value to a color index, only whole numbers from 0 to 255 are palette indices
     pub fn to_color(&self) -> Option<u8> {
         match self {
-            Number(v) => {
-                Some(*v as u8)
+            Number(_) | Int(_) => {
+                self.to_number().filter(|v| v.fract() == 0.0 && (0.0..=255.0).contains(v)).map(|v| v as u8)
             },
             _ => {
                 None
@@ -51,6 +123,15 @@ impl Value {
             Number(v) => {
                 format!("{:}", v)
             },
+            Int(v) => {
+                format!("{}", v)
+            },
+            Bool(v) => {
+                if *v { "TRUE".into() } else { "FALSE".into() }
+            },
+            Vec3(v) => {
+                format!("({}, {}, {})", v.x, v.y, v.z)
+            },
             Shape3D(sdf) => {
                 sdf.to_string()
             },
@@ -63,12 +144,71 @@ impl Value {
                 s += "]";
                 s.to_string()
             },
+            Quotation(values) => {
+                let mut s = "{ ".to_string();
+                for v in values {
+                    s += v.to_string().as_str();
+                    s += " ";
+                }
+                s += "}";
+                s
+            },
             Command(string) => {
                 string.clone()
             },
             Config(string) => {
                 string.clone()
+            },
+            Word(name) => {
+                name.clone()
+            },
+            String(string) => {
+                format!("\"{}\"", string)
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::evaluate;
+    use super::Value::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!((Int(3).to_number(), Number(2.5).to_number(), Bool(true).to_number()), (Some(3.0), Some(2.5), None));
+        assert_eq!((Number(4.0).to_int(), Number(4.5).to_int(), String("4".into()).to_int()), (Some(4), None, None));
+        assert_eq!((Int(0).to_bool(), Number(0.5).to_bool(), Bool(true).to_bool(), Array(vec![]).to_bool()), (Some(false), Some(true), Some(true), None));
+        assert_eq!(Array(vec![Int(1), Number(2.5), Int(3)]).to_vec3(), Some(vec3f(1.0, 2.5, 3.0)));
+        assert_eq!(Array(vec![Int(1), Bool(true), Int(3)]).to_vec3(), None);
+        assert_eq!(Array(vec![Int(1), Int(2)]).to_vec3(), None);
+        assert_eq!(Number(1.0).to_vec3(), None);
+        assert_eq!((Int(255).to_color(), Number(7.0).to_color(), Int(256).to_color(), Number(1.5).to_color(), Int(-1).to_color()), (Some(255), Some(7), None, None, None));
+    }
+
+    #[test]
+    fn conversion_words() {
+        let stack = evaluate("2.7 INT -2.7 INT 2 FLOAT 1 2 3.5 VEC3 \"a b\" TRUE").unwrap().stack;
+        assert_eq!(stack, vec![Int(2), Int(-2), Number(2.0), Vec3(vec3f(1.0, 2.0, 3.5)), String("a b".into()), Bool(true)]);
+        assert_eq!(stack.iter().map(|v| v.type_name()).collect::<Vec<_>>(), ["integer", "integer", "number", "vec3", "string", "bool"]);
+    }
+
+    #[test]
+    fn type_mismatches_name_both_types() {
+        let mismatch = |input: &str| match evaluate(input).err().map(|err| err.kind) {
+            Some(ErrorKind::TypeMismatch { expected, found, target }) => (expected, found, target),
+            kind => panic!("{}: {:?}", input, kind),
+        };
+        let expect = |expected: &str, found: &str, target: &str| (expected.to_string(), found.to_string(), target.to_string());
+
+        assert_eq!(mismatch("\"a\" INT"), expect("number", "string", "\"INT\""));
+        assert_eq!(mismatch("1 2 3 VEC3 BAKE"), expect("shape", "vec3", "\"BAKE\""));
+        assert_eq!(mismatch("0.1 SPHERE TRUE 1 2 TRANSLATE"), expect("number", "bool", "\"TRANSLATE\""));
+        assert_eq!(mismatch("0.1 SPHERE [ 1 \"a\" 3 ] TRANSLATE"), expect("number", "array", "\"TRANSLATE\""));
+        assert_eq!(mismatch("1 { } MAP"), expect("array", "integer", "\"MAP\""));
+        assert_eq!(mismatch("[ 1 ] 2 EACH"), expect("quotation", "integer", "\"EACH\""));
+        assert_eq!(mismatch("2.5 LEN"), expect("array or string", "number", "\"LEN\""));
+        assert_eq!(mismatch(": f IF 1 THEN ; \"a\" f"), expect("bool", "string", "\"IF\""));
+    }
+}
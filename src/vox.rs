@@ -0,0 +1,531 @@
+use crate::prelude::*;
+use crate::format::{Writer, Reader};
+
+/// MagicaVoxel limits models to 256 voxels per axis
+const MODEL_SIZE: i32 = 256;
+
+/// Append a MagicaVoxel chunk with content and children
+fn vox_chunk(w: &mut Writer, id: &[u8; 4], content: Writer, children: Writer) {
+    w.data.extend_from_slice(id);
+    w.i32(content.data.len() as i32);
+    w.i32(children.data.len() as i32);
+    w.data.extend(content.data);
+    w.data.extend(children.data);
+}
+
+/// Write a MagicaVoxel dictionary
+fn vox_dict(w: &mut Writer, pairs: &[(&str, String)]) {
+    w.i32(pairs.len() as i32);
+    for (key, value) in pairs {
+        w.string(key);
+        w.string(value);
+    }
+}
+
+/// The color index of each palette entry in the file, index i refers to entry i - 1. MagicaVoxel
+/// has 255 colors, the 256th entry takes the index of the closest of the others.
+fn vox_indices(palette: &Palette) -> [u8; 256] {
+    let mut indices = [0_u8; 256];
+    for (entry, index) in indices.iter_mut().enumerate().take(255) {
+        *index = entry as u8 + 1;
+    }
+
+    if let Some(last) = palette.colors_f.get(255) {
+        let metric = palette.metric();
+        let p = metric.transform(last[0], last[1], last[2]);
+        let mut best = (f32::MAX, 0);
+        for (entry, c) in palette.colors_f.iter().take(255).enumerate() {
+            let q = metric.transform(c[0], c[1], c[2]);
+            let d = (p[0] - q[0]).powi(2) + (p[1] - q[1]).powi(2) + (p[2] - q[2]).powi(2);
+            if d < best.0 {
+                best = (d, entry);
+            }
+        }
+        indices[255] = best.1 as u8 + 1;
+    }
+
+    indices
+}
+
+/// The color of the index in the default palette of MagicaVoxel: a 6x6x6 color cube without
+/// black, followed by ramps of red, green, blue and gray
+fn default_vox_color(index: usize) -> [u8; 3] {
+    if index < 216 {
+        let i = index.max(1) - 1;
+        let level = |step: usize| (255 - step * 51) as u8;
+        [level(i / 36), level(i / 6 % 6), level(i % 6)]
+    } else {
+        let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+        let v = ramp[(index - 216) % 10];
+        match (index - 216) / 10 {
+            0 => [v, 0, 0],
+            1 => [0, v, 0],
+            2 => [0, 0, v],
+            _ => [v, v, v],
+        }
+    }
+}
+
+impl World {
+
+    /// All voxels of the map in MagicaVoxel coordinates, which are z-up: x stays, y is -z, z is y.
+    pub fn vox_voxels(&self) -> Vec<(Vec3i, u8)> {
+        self.global_voxels().into_iter().map(|(g, color)| (vec3i(g.x, -g.z - 1, g.y), color)).collect()
+    }
+
+    /// Export the map as a MagicaVoxel .vox file. Scenes larger than 256 voxels per axis are
+    /// split into multiple models, which are placed by the scene graph.
+    pub fn export_vox(&self, path: &str, palette: &Palette) -> Result<(), RpuError> {
+        let io_error = |message: String| RpuError::new(ErrorKind::Io { path: path.into(), message });
+
+        let voxels = self.vox_voxels();
+        if voxels.is_empty() {
+            return Err(io_error("The world is empty, nothing to export.".into()));
+        }
+
+        let mut min = voxels[0].0;
+        let mut max = voxels[0].0;
+        for (p, _) in &voxels {
+            min = vec3i(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+            max = vec3i(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+        }
+
+        // Sort the voxels into models of at most MODEL_SIZE per axis
+        let mut models : FxHashMap<(i32, i32, i32), Vec<(Vec3i, u8)>> = FxHashMap::default();
+        for (p, color) in voxels {
+            let rel = p - min;
+            let key = (rel.x / MODEL_SIZE, rel.y / MODEL_SIZE, rel.z / MODEL_SIZE);
+            models.entry(key).or_default().push((rel - vec3i(key.0, key.1, key.2) * MODEL_SIZE, color));
+        }
+
+        let mut keys : Vec<(i32, i32, i32)> = models.keys().cloned().collect();
+        keys.sort();
+
+        let total = max - min + vec3i(1, 1, 1);
+        let sizes : Vec<Vec3i> = keys.iter().map(|key| vec3i(
+            (total.x - key.0 * MODEL_SIZE).min(MODEL_SIZE),
+            (total.y - key.1 * MODEL_SIZE).min(MODEL_SIZE),
+            (total.z - key.2 * MODEL_SIZE).min(MODEL_SIZE))).collect();
+
+        let indices = vox_indices(palette);
+        let mut children = Writer::new();
+
+        for (key, size) in keys.iter().zip(&sizes) {
+            let model = &models[key];
+
+            let mut content = Writer::new();
+            content.i32(size.x);
+            content.i32(size.y);
+            content.i32(size.z);
+            vox_chunk(&mut children, b"SIZE", content, Writer::new());
+
+            let mut content = Writer::new();
+            content.i32(model.len() as i32);
+            for (p, color) in model {
+                content.u8(p.x as u8);
+                content.u8(p.y as u8);
+                content.u8(p.z as u8);
+                content.u8(indices[*color as usize]);
+            }
+            vox_chunk(&mut children, b"XYZI", content, Writer::new());
+        }
+
+        // Scene graph: root transform -> group -> (transform -> shape) per model
+
+        let mut content = Writer::new();
+        content.i32(0);
+        vox_dict(&mut content, &[]);
+        content.i32(1);
+        content.i32(-1);
+        content.i32(-1);
+        content.i32(1);
+        vox_dict(&mut content, &[]);
+        vox_chunk(&mut children, b"nTRN", content, Writer::new());
+
+        let mut content = Writer::new();
+        content.i32(1);
+        vox_dict(&mut content, &[]);
+        content.i32(keys.len() as i32);
+        for index in 0..keys.len() {
+            content.i32(2 + index as i32 * 2);
+        }
+        vox_chunk(&mut children, b"nGRP", content, Writer::new());
+
+        for (index, key) in keys.iter().enumerate() {
+            let node = 2 + index as i32 * 2;
+
+            // MagicaVoxel positions a model by its center
+            let size = sizes[index];
+            let origin = min + vec3i(key.0, key.1, key.2) * MODEL_SIZE;
+            let t = origin + vec3i(size.x / 2, size.y / 2, size.z / 2);
+
+            let mut content = Writer::new();
+            content.i32(node);
+            vox_dict(&mut content, &[]);
+            content.i32(node + 1);
+            content.i32(-1);
+            content.i32(0);
+            content.i32(1);
+            vox_dict(&mut content, &[("_t", format!("{} {} {}", t.x, t.y, t.z))]);
+            vox_chunk(&mut children, b"nTRN", content, Writer::new());
+
+            let mut content = Writer::new();
+            content.i32(node + 1);
+            vox_dict(&mut content, &[]);
+            content.i32(1);
+            content.i32(index as i32);
+            vox_dict(&mut content, &[]);
+            vox_chunk(&mut children, b"nSHP", content, Writer::new());
+        }
+
+        let mut content = Writer::new();
+        for index in 0..256 {
+            let color = palette.colors.get(index).cloned().unwrap_or([0, 0, 0, 255]);
+            content.data.extend_from_slice(&color);
+        }
+        vox_chunk(&mut children, b"RGBA", content, Writer::new());
+
+        let mut w = Writer::new();
+        w.data.extend_from_slice(b"VOX ");
+        w.i32(150);
+        vox_chunk(&mut w, b"MAIN", Writer::new(), children);
+
+        std::fs::write(path, w.data).map_err(|err| io_error(err.to_string()))
+    }
+}
+
+/// Read a MagicaVoxel dictionary
+fn read_vox_dict(r: &mut Reader) -> Result<FxHashMap<String, String>, String> {
+    let mut dict = FxHashMap::default();
+    let count = r.i32()?;
+    for _ in 0..count {
+        let key = r.string()?;
+        let value = r.string()?;
+        dict.insert(key, value);
+    }
+    Ok(dict)
+}
+
+/// A node of the MagicaVoxel scene graph
+enum VoxNode {
+    Transform { child: i32, translation: Vec3i },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<i32> },
+}
+
+impl World {
+
+    /// Import a MagicaVoxel .vox file into the map. Models are placed by the translations of the
+    /// scene graph, rotations are ignored. Colors are mapped to the closest color of the palette.
+    /// Returns the number of imported voxels.
+    pub fn import_vox(&mut self, path: &str, palette: &Palette) -> Result<usize, RpuError> {
+        let data = std::fs::read(path).map_err(|err| RpuError::new(ErrorKind::Io { path: path.into(), message: err.to_string() }))?;
+        self.import_vox_bytes(&data, palette).map_err(|message| RpuError::new(ErrorKind::Io { path: path.into(), message }))
+    }
+
+    /// Import the content of a MagicaVoxel file, see import_vox()
+    pub fn import_vox_bytes(&mut self, data: &[u8], palette: &Palette) -> Result<usize, String> {
+        let mut r = Reader::new(data);
+
+        if r.bytes(4).ok() != Some(&b"VOX "[..]) {
+            return Err("Not a MagicaVoxel file.".into());
+        }
+        _ = r.i32()?;
+
+        // The MAIN chunk holds all other chunks as its children
+        if r.bytes(4)? != b"MAIN" {
+            return Err("Missing MAIN chunk.".into());
+        }
+        let content = r.size()?;
+        _ = r.i32()?;
+        _ = r.bytes(content)?;
+
+        let mut sizes : Vec<Vec3i> = vec![];
+        let mut models : Vec<Vec<(Vec3i, u8)>> = vec![];
+        let mut rgba : Option<Vec<[u8; 4]>> = None;
+        let mut nodes : FxHashMap<i32, VoxNode> = FxHashMap::default();
+
+        while !r.is_at_end() {
+            let id = r.bytes(4)?;
+            let content = r.size()?;
+            let children = r.size()?;
+            let mut c = Reader::new(r.bytes(content)?);
+            _ = r.bytes(children)?;
+
+            match id {
+                b"SIZE" => {
+                    sizes.push(vec3i(c.i32()?, c.i32()?, c.i32()?));
+                },
+                b"XYZI" => {
+                    let count = c.i32()?;
+                    let mut voxels = vec![];
+                    for _ in 0..count {
+                        let v = c.bytes(4)?;
+                        voxels.push((vec3i(v[0] as i32, v[1] as i32, v[2] as i32), v[3]));
+                    }
+                    models.push(voxels);
+                },
+                b"RGBA" => {
+                    let mut colors = vec![];
+                    for _ in 0..256 {
+                        let v = c.bytes(4)?;
+                        colors.push([v[0], v[1], v[2], v[3]]);
+                    }
+                    rgba = Some(colors);
+                },
+                b"nTRN" => {
+                    let node = c.i32()?;
+                    _ = read_vox_dict(&mut c)?;
+                    let child = c.i32()?;
+                    _ = c.i32()?;
+                    _ = c.i32()?;
+                    let frames = c.i32()?;
+                    let mut translation = Vec3i::zero();
+                    for frame in 0..frames {
+                        let dict = read_vox_dict(&mut c)?;
+                        if frame == 0 {
+                            if let Some(t) = dict.get("_t") {
+                                let v : Vec<i32> = t.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+                                if v.len() == 3 {
+                                    translation = vec3i(v[0], v[1], v[2]);
+                                }
+                            }
+                        }
+                    }
+                    nodes.insert(node, VoxNode::Transform { child, translation });
+                },
+                b"nGRP" => {
+                    let node = c.i32()?;
+                    _ = read_vox_dict(&mut c)?;
+                    let count = c.i32()?;
+                    let mut children = vec![];
+                    for _ in 0..count {
+                        children.push(c.i32()?);
+                    }
+                    nodes.insert(node, VoxNode::Group { children });
+                },
+                b"nSHP" => {
+                    let node = c.i32()?;
+                    _ = read_vox_dict(&mut c)?;
+                    let count = c.i32()?;
+                    let mut models = vec![];
+                    for _ in 0..count {
+                        models.push(c.i32()?);
+                        _ = read_vox_dict(&mut c)?;
+                    }
+                    nodes.insert(node, VoxNode::Shape { models });
+                },
+                _ => {
+                }
+            }
+        }
+
+        if sizes.len() != models.len() {
+            return Err("Invalid model data.".into());
+        }
+
+        // The position of the first voxel of each model. Without a scene graph all models are at the origin.
+        let mut origins : Vec<Vec3i> = vec![Vec3i::zero(); models.len()];
+        if nodes.contains_key(&0) {
+            // Nodes are visited once, a broken graph may contain cycles
+            let mut visited = FxHashSet::default();
+            let mut todo = vec![(0, Vec3i::zero())];
+            while let Some((node, offset)) = todo.pop() {
+                if !visited.insert(node) {
+                    continue;
+                }
+                match nodes.get(&node) {
+                    Some(VoxNode::Transform { child, translation }) => todo.push((*child, offset + *translation)),
+                    Some(VoxNode::Group { children }) => todo.extend(children.iter().map(|c| (*c, offset))),
+                    Some(VoxNode::Shape { models }) => {
+                        for model in models {
+                            if let Some(size) = sizes.get(*model as usize) {
+                                origins[*model as usize] = offset - vec3i(size.x / 2, size.y / 2, size.z / 2);
+                            }
+                        }
+                    },
+                    None => {}
+                }
+            }
+        }
+
+        // Map the colors of the file to the palette, index i refers to entry i - 1 of the RGBA
+        // chunk, files without one use the default palette
+        let mut colors = [0_u8; 256];
+        for (index, color) in colors.iter_mut().enumerate().skip(1) {
+            let c = match &rgba {
+                Some(rgba) => [rgba[index - 1][0], rgba[index - 1][1], rgba[index - 1][2]],
+                None => default_vox_color(index),
+            };
+            *color = palette.closest(c[0] as f32 / 255.0, c[1] as f32 / 255.0, c[2] as f32 / 255.0);
+        }
+
+        let res = self.map.resolution as i32;
+        let mut tiles : FxHashMap<(i32, i32, i32), Tile> = FxHashMap::default();
+        let mut count = 0;
+
+        for (model, voxels) in models.iter().enumerate() {
+            for (p, index) in voxels {
+                let p = origins[model] + *p;
+                // MagicaVoxel is z-up, see vox_voxels()
+                let g = vec3i(p.x, p.z, -p.y - 1);
+                let key = (g.x.div_euclid(res), g.y.div_euclid(res), g.z.div_euclid(res));
+
+                let tile = tiles.entry(key).or_insert_with(|| {
+                    self.get_tile(vec3i(key.0, key.1, key.2)).unwrap_or_else(|| Tile::with_storage(res as usize, self.map.storage))
+                });
+                tile.set_voxel(g.x.rem_euclid(res) as usize, g.y.rem_euclid(res) as usize, g.z.rem_euclid(res) as usize, Some((colors[*index as usize], 0)));
+                count += 1;
+            }
+        }
+
+        for (key, mut tile) in tiles {
+            tile.compress();
+            self.set_tile(vec3i(key.0, key.1, key.2), tile);
+        }
+        self.map.build_aabb();
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::*;
+
+    /// The voxels of the world as sorted global positions and RGB colors
+    fn colored_voxels(world: &World, palette: &Palette) -> Vec<(i32, i32, i32, [u8; 4])> {
+        let mut voxels : Vec<_> = world.global_voxels().into_iter().map(|(p, c)| (p.x, p.y, p.z, palette.at(c))).collect();
+        voxels.sort();
+        voxels
+    }
+
+    /// A minimal file with the given MAIN children
+    fn vox_file(children: &[u8]) -> Vec<u8> {
+        let mut data = b"VOX ".to_vec();
+        data.extend_from_slice(&150i32.to_le_bytes());
+        data.extend_from_slice(b"MAIN");
+        data.extend_from_slice(&0i32.to_le_bytes());
+        data.extend_from_slice(&(children.len() as i32).to_le_bytes());
+        data.extend_from_slice(children);
+        data
+    }
+
+    fn chunk(id: &[u8; 4], content: &[i32]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.extend_from_slice(&(content.len() as i32 * 4).to_le_bytes());
+        data.extend_from_slice(&0i32.to_le_bytes());
+        for v in content {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn vox_round_trip() {
+        let rpu = baked(TWO_MATERIALS);
+
+        let path = std::env::temp_dir().join(format!("rpu-round-trip-{}.vox", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        rpu.preview.export_vox(&path, &rpu.context.palette).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        _ = std::fs::remove_file(&path);
+
+        let mut world = World::new();
+        let count = world.import_vox_bytes(&data, &rpu.context.palette).unwrap();
+
+        let expected = colored_voxels(&rpu.preview, &rpu.context.palette);
+        assert_eq!(count, expected.len());
+        assert_eq!(colored_voxels(&world, &rpu.context.palette), expected);
+
+        // Imported voxels use the default material
+        assert!(tile_voxels(&world).iter().all(|v| v.4.1 == 0));
+    }
+
+    #[test]
+    fn last_palette_entry_takes_the_closest_color() {
+        let mut palette = Palette::new();
+        for i in 0..255 {
+            // Entry 42 is the closest to the last entry
+            if i == 42 {
+                palette.add(201, 10, 10);
+            } else {
+                palette.add(i, i, i);
+            }
+        }
+        palette.add(200, 10, 10);
+
+        let mut world = World::new();
+        let mut tile = Tile::new(world.map.resolution);
+        tile.set_voxel(0, 0, 0, Some((254, 0)));
+        tile.set_voxel(1, 0, 0, Some((255, 0)));
+        world.set_tile(vec3i(0, 0, 0), tile);
+
+        let path = std::env::temp_dir().join(format!("rpu-last-color-{}.vox", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        world.export_vox(&path, &palette).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        _ = std::fs::remove_file(&path);
+
+        let mut loaded = World::new();
+        loaded.import_vox_bytes(&data, &palette).unwrap();
+        let colors : Vec<u8> = tile_voxels(&loaded).iter().map(|v| v.4.0).collect();
+        assert_eq!(colors, vec![254, 42]);
+    }
+
+    #[test]
+    fn invalid_vox_data() {
+        let mut world = World::new();
+        let palette = Palette::new();
+
+        assert!(world.import_vox_bytes(b"NOPE", &palette).is_err());
+
+        // A negative chunk length
+        let mut data = b"VOX ".to_vec();
+        data.extend_from_slice(&150i32.to_le_bytes());
+        data.extend_from_slice(b"MAIN");
+        data.extend_from_slice(&(-1i32).to_le_bytes());
+        assert!(world.import_vox_bytes(&data, &palette).is_err());
+
+        // Content longer than the file
+        let mut data = vox_file(&chunk(b"SIZE", &[1, 1, 1]));
+        data.truncate(data.len() - 2);
+        assert!(world.import_vox_bytes(&data, &palette).is_err());
+    }
+
+    #[test]
+    fn default_palette() {
+        let mut children = chunk(b"SIZE", &[5, 1, 1]);
+        // White, the brightest red, green and blue and a dark gray of the default palette
+        let voxels : Vec<i32> = [1, 216, 226, 236, 252].iter().enumerate().map(|(x, i)| i32::from_le_bytes([x as u8, 0, 0, *i])).collect();
+        children.extend(chunk(b"XYZI", &[&[5], &voxels[..]].concat()));
+
+        let mut palette = Palette::new();
+        palette.add(255, 255, 255);
+        palette.add(255, 0, 0);
+        palette.add(0, 255, 0);
+        palette.add(0, 0, 255);
+        palette.add(128, 128, 128);
+
+        let mut world = World::new();
+        assert_eq!(world.import_vox_bytes(&vox_file(&children), &palette), Ok(5));
+        let mut voxels = world.global_voxels();
+        voxels.sort_by_key(|(p, _)| p.x);
+        let colors : Vec<u8> = voxels.iter().map(|(_, color)| *color).collect();
+        assert_eq!(colors, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn cyclic_scene_graph() {
+        let mut children = chunk(b"SIZE", &[1, 1, 1]);
+        children.extend(chunk(b"XYZI", &[1, i32::from_le_bytes([0, 0, 0, 5])]));
+        // Node 0 is a transform with itself as the child: node, empty dict, child, reserved, layer, no frames
+        children.extend(chunk(b"nTRN", &[0, 0, 0, -1, 0, 0]));
+
+        let mut world = World::new();
+        let mut palette = Palette::new();
+        palette.add(255, 0, 0);
+        assert_eq!(world.import_vox_bytes(&vox_file(&children), &palette), Ok(1));
+    }
+}
@@ -5,6 +5,11 @@ pub struct World {
     pub camera              : Camera,
 
     pub map                 : Map,
+
+    /// The name of the palette the voxel colors refer to
+    pub palette             : String,
+    /// The materials the material bytes of the voxels refer to, empty if none were baked
+    pub materials           : Vec<Material>,
 }
 
 impl World {
@@ -19,6 +24,9 @@ impl World {
             camera,
 
             map,
+
+            palette         : String::new(),
+            materials       : vec![],
         }
     }
 
@@ -60,7 +68,7 @@ impl World {
                     //let cam_off = vec2f(0.5, 0.5);
                     // let ray = self.camera.create_ray(uv, screen, cam_off);
 
-                    let mut ray;
+                    let ray;
 
                     if context.iso_state {
                         ray = self.camera.create_iso_ray(uv, screen, cam_off);
@@ -80,121 +88,19 @@ impl World {
                                 if let Some(hit) = self.dda_recursive(&ray) {
                                     //color = [hit.normal.x.abs(), hit.normal.y.abs(), hit.normal.z.abs(), 1.0];
                                     color = context.palette.at_f_to_linear(hit.value.0);
+                                    if context.shading {
+                                        let light = self.shade(&ray, &hit, context);
+                                        color = [color[0] * light, color[1] * light, color[2] * light, 1.0];
+                                    }
                                     hit_something = true;
                                     hit_distance = hit.distance;
                                 }
-                            } else {
-
-                                /*
-                                let max_depth = 2;
-
-                                let mut acc = Vec3f::zero();
-                                let mut mask = Vec3f::one();
-
-                                let pi = std::f32::consts::PI;
-                                let mut hit_something = false;
-                                /*
-                                for _depth in 0..max_depth {
-
-                                    if let Some(hit) = self.dda_recursive(&ray) {
-
-                                        hit_something = true;
-
-                                        let n = hit.normal;
-                                        let nl = n * signum(-dot(n, ray.d));
-
-                                        let material = &context.materials[hit.value.1 as usize];
-
-                                        let roughness = material.roughness;
-                                        let alpha = roughness * roughness;
-                                        let metallic = material.metallic;
-                                        let reflectance = material.reflectance;
-                                        let diffuse = context.palette.at_vec_to_linear(hit.value.0);
-                                        let color = diffuse;
-                                        let emission = material.emission * diffuse;
-
-                                        let mut brdf = vec3f(0.0, 0.0, 0.0);
-
-                                        let light_pos = vec3f(0.0, 6.0, 0.0);
-                                        let light_radius = 1.0;
-                                        let light_emission = vec3f(200.0, 200.0, 200.0);
-
-                                        let x = hit.hitpoint - 0.005 * n;
-
-                                        if reflectance == 1.0 || rng.gen::<f32>() < reflectance {
-
-                                            #[inline(always)]
-                                            pub fn mix(a: &f32, b: &f32, v: f32) -> f32 {
-                                                (1.0 - v) * a + b * v
-                                            }
-
-                                            let l0 = light_pos - x;
-                                            let cos_a_max = sqrt(1. - clamp(light_radius * light_radius / dot(l0, l0), 0.0, 1.0));
-                                            let cosa = mix(&cos_a_max, &1.0, rng.gen());
-                                            let l = jitter(l0, 2.0 * pi * rng.gen::<f32>(), sqrt(1.0 - cosa*cosa), cosa);
-
-                                            if let Some(_hit_refl) = self.dda_recursive(&Ray::new(x, l)) {
-
-                                            } else {
-                                                // No hit, we assume we hit it for now
-
-                                                let omega = 2.0 * pi * (1.0 - cos_a_max);
-                                                brdf += (light_emission * clamp(ggx(nl, ray.d, l, roughness, metallic),0.0,1.0) * omega) / pi;
-                                            }
-
-                                            let xsi_1 = rng.gen::<f32>();
-                                            let xsi_2 = rng.gen::<f32>();
-                                            let phi = atan((alpha * sqrt(xsi_1)) / sqrt(1.0 - xsi_1));
-                                            let theta = 2.0 * pi * xsi_2;
-                                            let direction = angle_to_dir(nl, theta, phi);
-                                            ray = Ray::new(x, direction);
-                                            acc += mask * emission + mask * color * brdf;
-                                            mask *= color;
-                                        } else {
-
-                                            #[inline(always)]
-                                            pub fn mix(a: &f32, b: &f32, v: f32) -> f32 {
-                                                (1.0 - v) * a + b * v
-                                            }
-
-                                            let r2 = rng.gen();
-                                            let d = jitter(nl, 2.0 * pi * rng.gen::<f32>(), sqrt(r2), sqrt(1.0 - r2));
-                                            let mut e = Vec3f::zero();
-
-                                            let l0 = light_pos - x;
-
-                                            let cos_a_max = sqrt(1.0 - clamp(light_radius * light_radius / dot(l0, l0), 0., 1.));
-                                            let cosa = mix(&cos_a_max, &1.0, rng.gen());
-                                            let l = jitter(l0, 2.0 * pi * rng.gen::<f32>(), sqrt(1.0 - cosa * cosa), cosa);
-
-                                            if let Some(_hit_refl) = self.dda_recursive(&Ray::new(x, l)) {
-
-                                            } else {
-                                                // No hit, we assume we hit it for now
-
-                                                let omega = 2.0 * pi * (1.0 - cos_a_max);
-                                                e += (light_emission * clamp(dot(l, n),0.0,1.0) * omega) / pi;
-                                            }
-
-                                            acc += mask * emission + mask * color * e;
-                                            mask *= color;
-                                            ray = Ray::new(x, d);
-                                        }
-                                    } else {
-                                        acc += mask * vec3f(0.5, 0.5, 0.5);
-                                        break;
-                                    }
-                                }*/
-
-                                color = [acc.x, acc.y, acc.z, 1.0];
-
-                                if hit_something {
-                                    // Clip color to the palette
-                                    let index = context.palette.closest(color[0].powf(0.4545), color[1].powf(0.4545), color[2].powf(0.4545));
-                                    color = context.palette.at_f(index);
-                                } else {
-                                    color = [0.15, 0.15, 0.15, 1.0];
-                                }*/
+                            } else
+                            if let Some((c, distance)) = self.path_trace(&ray, context, &mut rng) {
+                                // The accumulated image is clipped to the palette in render_passes()
+                                color = [c.x, c.y, c.z, 1.0];
+                                hit_something = true;
+                                hit_distance = distance;
                             }
                         }
                     }
@@ -244,6 +150,203 @@ impl World {
         //println!("renter time {:?}, iter: {}", _stop - _start, iteration);
     }
 
+    /// Render all passes of the context, the accumulated path tracer output is clipped to the palette
+    pub fn render_passes(&self, buffer: &mut ColorBuffer, context: &Context) {
+        for i in 0..context.samples {
+            self.render(buffer, context, i);
+        }
+
+        if context.render_state {
+            context.palette.quantize(&mut buffer.pixels, buffer.width, context.dither);
+        }
+    }
+
+    /// The light arriving at the hit of the preview: Lambert lighting from the sun with hard
+    /// shadows, plus an ambient term darkened by voxel ambient occlusion.
+    fn shade(&self, ray: &Ray, hit: &HitRecord, context: &Context) -> f32 {
+        // The voxel normal points into the voxel, a hit at the ray origin has none
+        let n = if hit.normal == Vec3f::zero() { ray.d } else { hit.normal };
+        let nl = n * signum(-dot(n, ray.d));
+
+        let sun = normalize(context.sun);
+        let mut direct = max(dot(nl, sun), 0.0);
+        if direct > 0.0 && context.shadows && self.dda_recursive(&Ray::new(hit.hitpoint + nl * 0.005, sun)).is_some() {
+            direct = 0.0;
+        }
+
+        let ao = if context.ao_state { self.ambient_occlusion(hit.hitpoint, nl) } else { 1.0 };
+
+        0.3 * ao + 0.8 * direct
+    }
+
+    /// All voxels of the map with their global voxel coordinate and color
+    pub fn global_voxels(&self) -> Vec<(Vec3i, u8)> {
+        let res = self.map.resolution as i32;
+        let mut voxels = vec![];
+
+        for (key, tile) in &self.map.tiles {
+            let size = tile.size as i32;
+            for z in 0..size {
+                for y in 0..size {
+                    for x in 0..size {
+                        if let Some((color, _)) = tile.get_voxel(x as usize, y as usize, z as usize) {
+                            // Tiles which were resized keep their own size, scale them to the map resolution
+                            let g = vec3i(key.0 * res + x * res / size, key.1 * res + y * res / size, key.2 * res + z * res / size);
+                            voxels.push((g, color));
+                        }
+                    }
+                }
+            }
+        }
+
+        voxels
+    }
+
+    /// Returns true if the voxel at the given global voxel coordinate is set
+    pub fn is_voxel_set(&self, v: Vec3i) -> bool {
+        let size = self.map.tile_size() as i32;
+        let key = (v.x.div_euclid(size), v.y.div_euclid(size), v.z.div_euclid(size));
+        if let Some(tile) = self.map.tiles.get(&key) {
+            tile.get_voxel(v.x.rem_euclid(size) as usize, v.y.rem_euclid(size) as usize, v.z.rem_euclid(size) as usize).is_some()
+        } else {
+            false
+        }
+    }
+
+    /// Voxel ambient occlusion of the face with the normal nl at the hitpoint. Every corner of
+    /// the face is darkened by the voxels next to it, and the corners are blended across the face.
+    fn ambient_occlusion(&self, hitpoint: Vec3f, nl: Vec3f) -> f32 {
+        let size = self.map.tile_size() as f32;
+
+        let axis = if nl.x.abs() > 0.5 { 0 } else if nl.y.abs() > 0.5 { 1 } else { 2 };
+        let u = (axis + 1) % 3;
+        let v = (axis + 2) % 3;
+
+        let unit = |a: usize, s: i32| {
+            let mut o = Vec3i::zero();
+            o[a] = s;
+            o
+        };
+
+        // The empty voxel in front of the face
+        let front = Vec3i::from(floor((hitpoint + nl * (0.5 / size)) * size));
+        let p = hitpoint * size;
+        let fu = p[u] - p[u].floor();
+        let fv = p[v] - p[v].floor();
+
+        let mut ao = 0.0;
+        for (su, wu) in [(-1, 1.0 - fu), (1, fu)] {
+            for (sv, wv) in [(-1, 1.0 - fv), (1, fv)] {
+                let side1 = self.is_voxel_set(front + unit(u, su));
+                let side2 = self.is_voxel_set(front + unit(v, sv));
+                let corner = self.is_voxel_set(front + unit(u, su) + unit(v, sv));
+                let occlusion = if side1 && side2 { 3 } else { side1 as i32 + side2 as i32 + corner as i32 };
+                ao += (1.0 - occlusion as f32 / 3.0) * wu * wv;
+            }
+        }
+
+        0.4 + 0.6 * ao
+    }
+
+    /// Trace the path of the ray through the world, returns the gathered light and the
+    /// distance of the first hit. Diffuse and specular bounces are chosen by the reflectance
+    /// of the material, every bounce samples the area light directly for soft shadows.
+    fn path_trace(&self, ray: &Ray, context: &Context, rng: &mut ThreadRng) -> Option<(Vec3f, f32)> {
+
+        let max_depth = 3;
+
+        let pi = std::f32::consts::PI;
+        let light = &context.light;
+
+        let mut ray = ray.clone();
+        let mut acc = Vec3f::zero();
+        let mut mask = Vec3f::one();
+        let mut first_distance = None;
+
+        let voxel_size = 1.0 / self.map.tile_size() as f32;
+        let mut inside_transparent = false;
+        let mut transmissions = 0;
+
+        let mut depth = 0;
+        while depth < max_depth {
+
+            if let Some(hit) = self.dda_recursive(&ray) {
+
+                if first_distance.is_none() {
+                    first_distance = Some(hit.distance);
+                }
+
+                let material = &context.materials[hit.value.1 as usize];
+                let color = context.palette.at_vec_to_linear(hit.value.0);
+
+                // Pass through transparent voxels, tinted by the color of the first one
+                if material.transparency > 0.0 && transmissions < 256 && rng.gen::<f32>() < material.transparency {
+                    if !inside_transparent {
+                        mask *= color;
+                        inside_transparent = true;
+                    }
+                    transmissions += 1;
+                    ray = Ray::new(hit.hitpoint + ray.d * voxel_size, ray.d);
+                    continue;
+                }
+                inside_transparent = false;
+                depth += 1;
+
+                // The voxel normal points into the voxel, a hit at the ray origin has none
+                let n = if hit.normal == Vec3f::zero() { ray.d } else { hit.normal };
+                let nl = n * signum(-dot(n, ray.d));
+
+                let roughness = material.roughness;
+                let alpha = roughness * roughness;
+                let emission = color * material.emission;
+
+                let x = hit.hitpoint + nl * 0.005;
+
+                // Sample a direction towards the light
+                let l0 = light.position - x;
+                let cos_a_max = sqrt(1.0 - clamp(light.radius * light.radius / dot(l0, l0), 0.0, 1.0));
+                let cosa = cos_a_max + (1.0 - cos_a_max) * rng.gen::<f32>();
+                let l = jitter(l0, 2.0 * pi * rng.gen::<f32>(), sqrt(1.0 - cosa * cosa), cosa);
+                let omega = 2.0 * pi * (1.0 - cos_a_max);
+                let visible = self.dda_recursive(&Ray::new(x, l)).is_none_or(|h| context.materials[h.value.1 as usize].transparency > 0.0);
+
+                if material.reflectance > 0.0 && rng.gen::<f32>() < material.reflectance {
+                    let mut brdf = Vec3f::zero();
+                    if visible {
+                        brdf += (light.emission * clamp(ggx(nl, ray.d, l, roughness, material.metallic), 0.0, 1.0) * omega) / pi;
+                    }
+
+                    let xsi_1 = rng.gen::<f32>();
+                    let xsi_2 = rng.gen::<f32>();
+                    let phi = atan((alpha * sqrt(xsi_1)) / sqrt(1.0 - xsi_1));
+                    let theta = 2.0 * pi * xsi_2;
+                    let reflected = ray.d - nl * 2.0 * dot(ray.d, nl);
+
+                    acc += mask * emission + mask * color * brdf;
+                    mask *= color;
+                    ray = Ray::new(x, angle_to_dir(reflected, theta, phi));
+                } else {
+                    let mut e = Vec3f::zero();
+                    if visible {
+                        e += (light.emission * clamp(dot(l, nl), 0.0, 1.0) * omega) / pi;
+                    }
+
+                    let r2 = rng.gen::<f32>();
+                    let d = jitter(nl, 2.0 * pi * rng.gen::<f32>(), sqrt(r2), sqrt(1.0 - r2));
+
+                    acc += mask * emission + mask * color * e;
+                    mask *= color;
+                    ray = Ray::new(x, d);
+                }
+            } else {
+                acc += mask * vec3f(0.5, 0.5, 0.5);
+                break;
+            }
+        }
+
+        first_distance.map(|distance| (acc, distance))
+    }
+
     pub fn hit_at(&self, pos: Vec2f, buffer: &ColorBuffer, iso_state: bool) -> Option<HitRecord> {
 
         let x: f32 = pos.x / buffer.width as f32;
@@ -432,88 +535,99 @@ impl World {
         self.map.clear();
     }
 
-    pub fn compile(&mut self, bake: &Bake, _context: &mut Context) {
+    pub fn compile(&mut self, bake: &Bake, context: &mut Context) {
 
-        let repeat = 3;
+        self.palette = context.palette.name.clone();
+        self.materials = context.materials.clone();
 
         let mut rng: ThreadRng = thread_rng();
 
         if let Some(sdf) = &bake.sdf {
-            let local_bbox: AABB = sdf.create_local_bbox();
-            let size = local_bbox.get_size();
+            let unbounded_bbox: AABB = sdf.create_local_bbox();
+            let local_bbox: AABB = unbounded_bbox.bound(&bake.limit);
+
+            // Translated shapes are baked at the world origin so that their transforms place them,
+            // other shapes rest on the ground at the location of the bake
             let mut position = Vec3f::zero();
 
-            match bake.location {
-                Location::Middle => {
-                    position.x = 0.5;
-                    position.y = 0.0 + size.y / 2.0;
-                    position.z = 0.5;
-                },
-                Location::FrontLeft => {
-                    position.x = 0.0 + size.x / 2.0;
-                    position.y = 0.0 + size.y / 2.0;
-                    position.z = 1.0 - size.z / 2.0;
-                },
-                _ => {
+            if !sdf.is_translated() || !unbounded_bbox.is_finite() {
+                match bake.location {
+                    Location::Middle => {
+                        position.x = 0.5 - (local_bbox.min.x + local_bbox.max.x) / 2.0;
+                        position.y = 0.0 - local_bbox.min.y;
+                        position.z = 0.5 - (local_bbox.min.z + local_bbox.max.z) / 2.0;
+                    },
+                    Location::FrontLeft => {
+                        position.x = 0.0 - local_bbox.min.x;
+                        position.y = 0.0 - local_bbox.min.y;
+                        position.z = 1.0 - local_bbox.max.z;
+                    },
+                    _ => {
+                    }
                 }
             }
 
-            for r in 0..repeat {
+            let bbox: AABB = AABB { min: local_bbox.min + position, max: local_bbox.max + position };
+            let tiles: Vec<Vec3<i32>> = self.map.create_tiles_aabb(&bbox);
 
-                let bbox: AABB = sdf.create_bbox(position);
-                let tiles: Vec<Vec3<i32>> = self.map.create_tiles_aabb(&bbox);
+            // let tiles: Vec<Vec3<i32>> = vec![vec3i(0, 0, 0)];
+            //println!("{:?}", tiles);
 
-                // let tiles: Vec<Vec3<i32>> = vec![vec3i(0, 0, 0)];
-                //println!("{:?}", tiles);
+            // One color per primitive, the voxel gets the color of the primitive forming the surface
+            let materials = sdf.get_materials();
+            let colors : Vec<u8> = sdf.get_colors(&mut rng).iter().zip(&materials).map(|(color, material)| {
+                context.materials[*material as usize].albedo.unwrap_or(*color)
+            }).collect();
 
-                let color = sdf.get_color(&mut rng);
+            for tile_key in &tiles {
+                if let Some(mut tile) = self.get_tile(*tile_key) {
+                    let size = tile.size;
 
-                for tile_key in &tiles {
-                    if let Some(mut tile) = self.get_tile(*tile_key) {
-                        let size = tile.size;
+                    // println!("{}", tile_key);
 
-                        // println!("{}", tile_key);
+                    for y in 0..size {
+                        for x in 0..size {
+                            for z in 0..size {
+                                let pos = self.to_world_coord(*tile_key, vec3i(x as i32, y as i32, z as i32));
 
-                        for y in 0..size {
-                            for x in 0..size {
-                                for z in 0..size {
-                                    let pos = self.to_world_coord(*tile_key, vec3i(x as i32, y as i32, z as i32));
-
-                                    /*
+                                /*
 
-                                    let p = pos - vec3f(0.0, 0.2, 0.0) - hp; let b = vec3f(0.2, 0.2, 0.2);
-                                    let q = abs(p) - b;
-                                    let d1 = length(max(q,vec3f(0.0, 0.0, 0.0))) + min(max(q.x,max(q.y,q.z)),0.0);
-                                    if d1 < 0.0 {
-                                        tile.set_voxel(x, y, z, Some((10, 10)));
-                                    }*/
+                                let p = pos - vec3f(0.0, 0.2, 0.0) - hp; let b = vec3f(0.2, 0.2, 0.2);
+                                let q = abs(p) - b;
+                                let d1 = length(max(q,vec3f(0.0, 0.0, 0.0))) + min(max(q.x,max(q.y,q.z)),0.0);
+                                if d1 < 0.0 {
+                                    tile.set_voxel(x, y, z, Some((10, 10)));
+                                }*/
 
-                                    /*
-                                    let p = abs(pos.xy() - vec2f(0.0, 0.3) - hp.xy()) - vec2f(0.2, 0.2);
-                                    let mut d = length(max(p,Vec2f::new(0.0, 0.0))) + min(max(p.x,p.y),0.0);
+                                /*
+                                let p = abs(pos.xy() - vec2f(0.0, 0.3) - hp.xy()) - vec2f(0.2, 0.2);
+                                let mut d = length(max(p,Vec2f::new(0.0, 0.0))) + min(max(p.x,p.y),0.0);
 
-                                    d = abs(d) - 0.05;
+                                d = abs(d) - 0.05;
 
-                                    let h = 0.2;
-                                    let w = vec2f( d, abs(pos.z - hp.z) - h );
-                                    d = min(max(w.x,w.y),0.0) + length(max(w,vec2f(0.0, 0.0)));
-                                    */
+                                let h = 0.2;
+                                let w = vec2f( d, abs(pos.z - hp.z) - h );
+                                d = min(max(w.x,w.y),0.0) + length(max(w,vec2f(0.0, 0.0)));
+                                */
 
-                                    let d = sdf.distance(pos, position);
+                                let (d, leaf) = sdf.distance_leaf(pos - position);
 
 
-                                    if d < 0.0 {
-                                        tile.set_voxel(x, y, z, Some((color, 10)));
-                                    }
+                                if d < 0.0 {
+                                    tile.set_voxel(x, y, z, Some((colors[leaf], materials[leaf])));
                                 }
                             }
                         }
+                    }
 
+                    // Tiles the shape does not touch are not kept
+                    tile.compress();
+                    if tile.is_empty() {
+                        self.map.tiles.remove(&(tile_key.x, tile_key.y, tile_key.z));
+                    } else {
                         self.set_tile(*tile_key, tile);
                     }
                 }
-
-                position.x += size.x + 0.01;
             }
 
             self.map.build_aabb();
@@ -524,7 +638,7 @@ impl World {
     pub fn to_world_coord(&self, key: Vec3i, tile_key: Vec3i) -> Vec3f {
         let mut wc = Vec3f::from(key);
 
-        let size = Map::tile_size() as f32;
+        let size = self.map.tile_size() as f32;
         wc.x += tile_key.x as f32 / size;
         wc.y += tile_key.y as f32 / size;
         wc.z += tile_key.z as f32 / size;
@@ -534,7 +648,7 @@ impl World {
 
     /// Converts the world coordinate to hit keys
     pub fn to_tile_coord(&self, wc: Vec3f) -> (Vec3i, Vec3i) {
-        let size = Map::tile_size() as f32;
+        let size = self.map.tile_size() as f32;
         let key = Vec3i::new(wc.x as i32, wc.y as i32, wc.z as i32);
         let tile_key = Vec3i::new((frac(wc.x) * size) as i32, (frac(wc.y) * size) as i32, (frac(wc.z) * size) as i32);
         (key, tile_key)
@@ -549,4 +663,212 @@ impl World {
             stop.as_millis()
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use crate::test_util::baked;
+
+    /// A sphere baked into the preview world
+    fn sphere() -> RPU {
+        baked("0.3 SPHERE BAKE")
+    }
+
+    /// The size of the baked voxels in world units
+    fn extent(world: &World) -> Vec3f {
+        let voxels = world.global_voxels();
+        let mut lo = voxels[0].0;
+        let mut hi = voxels[0].0;
+        for (v, _) in &voxels {
+            lo = vec3i(lo.x.min(v.x), lo.y.min(v.y), lo.z.min(v.z));
+            hi = vec3i(hi.x.max(v.x), hi.y.max(v.y), hi.z.max(v.z));
+        }
+        let res = world.map.resolution as f32;
+        vec3f((hi.x - lo.x + 1) as f32 / res, (hi.y - lo.y + 1) as f32 / res, (hi.z - lo.z + 1) as f32 / res)
+    }
+
+    /// A ray straight down onto the top of the baked shape, which faces the light
+    fn ray_from_above(world: &World) -> Ray {
+        let aabb = world.map.aabb.clone().unwrap();
+        let center = (aabb.min + aabb.max) / 2.0;
+        Ray::new(vec3f(center.x, aabb.max.y + 1.0, center.z), vec3f(0.0, -1.0, 0.0))
+    }
+
+    /// The mean radiance of the given number of path traced samples
+    fn mean_radiance(world: &World, ray: &Ray, context: &Context, samples: usize) -> Vec3f {
+        let mut rng = thread_rng();
+        let mut sum = Vec3f::zero();
+        for _ in 0..samples {
+            let (c, _) = world.path_trace(ray, context, &mut rng).unwrap();
+            assert!(c.x.is_finite() && c.y.is_finite() && c.z.is_finite());
+            assert!(c.x >= 0.0 && c.y >= 0.0 && c.z >= 0.0);
+            sum += c;
+        }
+        sum / samples as f32
+    }
+
+    #[test]
+    fn path_trace_hits_the_lit_surface() {
+        let rpu = sphere();
+        let ray = ray_from_above(&rpu.preview);
+
+        let (_, distance) = rpu.preview.path_trace(&ray, &rpu.context, &mut thread_rng()).unwrap();
+        assert!(distance > 0.0 && distance < 2.0);
+
+        let c = mean_radiance(&rpu.preview, &ray, &rpu.context, 1000);
+        assert!(c.x + c.y + c.z > 0.0);
+    }
+
+    #[test]
+    fn path_trace_misses_the_empty_sky() {
+        let rpu = sphere();
+        let mut ray = ray_from_above(&rpu.preview);
+        ray = Ray::new(ray.o, vec3f(0.0, 1.0, 0.0));
+
+        assert!(rpu.preview.path_trace(&ray, &rpu.context, &mut thread_rng()).is_none());
+    }
+
+    #[test]
+    fn path_trace_matches_the_lit_plane() {
+        let mut rpu = baked("2 0.1 2 BOX BAKE");
+        let ray = ray_from_above(&rpu.preview);
+        let color = rpu.context.palette.at_vec_to_linear(rpu.preview.global_voxels()[0].1);
+
+        // Without a light the diffuse bounce off the plane always escapes to the sky of 0.5
+        rpu.context.light.emission = Vec3f::zero();
+        let c = mean_radiance(&rpu.preview, &ray, &rpu.context, 100);
+        assert!(length(c - color * 0.5) < 1e-4, "{:?}", c);
+
+        // A sphere light straight above adds its radiance times the squared ratio of its radius to its distance
+        let top = rpu.preview.global_voxels().iter().map(|(v, _)| v.y + 1).max().unwrap() as f32 / rpu.preview.map.resolution as f32;
+        let hit = vec3f(ray.o.x, top, ray.o.z);
+        rpu.context.light = Light { position: hit + vec3f(0.0, 2.0, 0.0), radius: 0.5, emission: vec3f(8.0, 8.0, 8.0) };
+        let expected = color * (8.0 * 0.5 * 0.5 / (2.0 * 2.0) + 0.5);
+        let c = mean_radiance(&rpu.preview, &ray, &rpu.context, 20000);
+        assert!(length(c - expected) < 0.01 * length(expected), "{:?} vs {:?}", c, expected);
+    }
+
+    #[test]
+    fn bake_keeps_large_shapes() {
+        let rpu = baked("5 0.5 0.5 BOX BAKE");
+        let e = extent(&rpu.preview);
+        assert!((e.x - 5.0).abs() < 0.1, "{:?}", e);
+        assert!((e.y - 0.5).abs() < 0.1 && (e.z - 0.5).abs() < 0.1, "{:?}", e);
+    }
+
+    #[test]
+    fn bake_places_shapes_by_their_transforms() {
+        let lowest = |world: &World| world.global_voxels().iter().map(|(v, _)| *v).min_by_key(|v| (v.x, v.y, v.z)).unwrap();
+        let resolution = Map::default_resolution() as i32;
+
+        let at_origin = baked("0.1 SPHERE 0 0 0 TRANSLATE BAKE");
+        let translated = baked("0.1 SPHERE 1 0 0 TRANSLATE BAKE");
+        assert_eq!(lowest(&translated.preview), lowest(&at_origin.preview) + vec3i(resolution, 0, 0));
+        assert!(lowest(&at_origin.preview).y < 0);
+
+        // Two shapes baked apart do not overlap
+        let both = baked("0.1 SPHERE 0 0 0 TRANSLATE BAKE 0.1 SPHERE 1 0 0 TRANSLATE BAKE");
+        assert_eq!(both.preview.global_voxels().len(), at_origin.preview.global_voxels().len() + translated.preview.global_voxels().len());
+    }
+
+    #[test]
+    fn bake_rests_untransformed_shapes_on_the_ground() {
+        let resolution = Map::default_resolution() as i32;
+
+        // The front left corner of the bbox sits at x = 0, y = 0 and z = 1. Voxels are sampled at
+        // their lower corner, so the voxels on the faces at x = 0 and y = 0 stay empty.
+        for input in ["0.2 0.4 0.6 BOX BAKE", "0.2 0.4 0.6 BOX 0 0 1 ROTATE BAKE"] {
+            let voxels = baked(input).preview.global_voxels();
+            let lo = voxels.iter().fold(voxels[0].0, |a, (v, _)| vec3i(a.x.min(v.x), a.y.min(v.y), a.z.min(v.z)));
+            let hi = voxels.iter().fold(voxels[0].0, |a, (v, _)| vec3i(a.x.max(v.x), a.y.max(v.y), a.z.max(v.z)));
+            assert_eq!((lo.x, lo.y, hi.z), (1, 1, resolution - 1), "{}", input);
+        }
+
+        let e = extent(&baked("0.2 0.4 0.6 BOX BAKE").preview);
+        assert!((e.x - 0.2).abs() < 0.03 && (e.y - 0.4).abs() < 0.03 && (e.z - 0.6).abs() < 0.03, "{:?}", e);
+    }
+
+    #[test]
+    fn bake_bounds_infinite_shapes() {
+        let rpu = baked("0.1 0.1 0.1 BOX 1 0 0 REPEAT-INFINITE BAKE");
+        let e = extent(&rpu.preview);
+        let limit = Bake::new().limit.get_size();
+        assert!(e.x > 1.0 && e.x <= limit.x + 0.1, "{:?}", e);
+        assert!(e.y < 0.2 && e.z < 0.2, "{:?}", e);
+    }
+
+    #[test]
+    fn bake_keeps_every_limited_repetition() {
+        let rpu = baked("0.1 0.1 0.1 BOX 30 1 1 0.5 REPEAT-LIMITED BAKE");
+
+        // Each instance is a run of neighbouring x coordinates, separated by the spacing
+        let mut xs: Vec<i32> = rpu.preview.global_voxels().iter().map(|(v, _)| v.x).collect();
+        xs.sort();
+        xs.dedup();
+        let instances = 1 + xs.windows(2).filter(|w| w[1] - w[0] > 1).count();
+        assert_eq!(instances, 30);
+
+        let e = extent(&rpu.preview);
+        assert!((e.x - (29.0 * 0.5 + 0.1)).abs() < 0.1, "{:?}", e);
+    }
+
+    /// The preview shading of the first hit of the ray
+    fn shade_hit(world: &World, ray: &Ray, context: &Context) -> f32 {
+        let hit = world.dda_recursive(ray).unwrap();
+        world.shade(ray, &hit, context)
+    }
+
+    #[test]
+    fn shade_lights_faces_towards_the_sun() {
+        let mut rpu = baked("2 0.1 2 BOX BAKE");
+        let ray = ray_from_above(&rpu.preview);
+
+        // An open face gets the full ambient term plus the sun by the cosine of its angle
+        rpu.context.sun = vec3f(0.0, 2.0, 0.0);
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - 1.1).abs() < 1e-4);
+        rpu.context.sun = vec3f(1.0, 1.0, 0.0);
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - (0.3 + 0.8 * 0.5f32.sqrt())).abs() < 1e-4);
+        rpu.context.sun = vec3f(0.0, -1.0, 0.0);
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - 0.3).abs() < 1e-4);
+    }
+
+    #[test]
+    fn shade_casts_shadows() {
+        let mut rpu = baked("2 0.1 2 BOX 0.5 0.1 0.5 BOX 0 1 0 TRANSLATE UNION BAKE");
+        rpu.context.sun = vec3f(0.0, 1.0, 0.0);
+
+        // Between the slab and the box floating above it
+        let aabb = rpu.preview.map.aabb.clone().unwrap();
+        let center = (aabb.min + aabb.max) / 2.0;
+        let ray = Ray::new(vec3f(center.x, 0.5, center.z), vec3f(0.0, -1.0, 0.0));
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - 0.3).abs() < 1e-4);
+
+        rpu.context.shadows = false;
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - 1.1).abs() < 1e-4);
+
+        // Outside of the shadow of the box
+        rpu.context.shadows = true;
+        let ray = Ray::new(vec3f(center.x + 0.7, 0.5, center.z), vec3f(0.0, -1.0, 0.0));
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - 1.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn shade_darkens_corners() {
+        let mut rpu = baked("2 0.1 2 BOX 0.2 0.4 0.2 BOX 0 0.2 0 TRANSLATE UNION BAKE");
+        rpu.context.sun = vec3f(0.0, 1.0, 0.0);
+
+        // The slab right next to the pillar
+        let ray = Ray::new(vec3f(-0.09, 1.0, 0.01), vec3f(0.0, -1.0, 0.0));
+        let occluded = shade_hit(&rpu.preview, &ray, &rpu.context);
+        assert!((0.3 * 0.4 + 0.8..1.1 - 1e-3).contains(&occluded), "{}", occluded);
+
+        rpu.context.ao_state = false;
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - 1.1).abs() < 1e-4);
+
+        // Away from the pillar nothing occludes the slab
+        rpu.context.ao_state = true;
+        let ray = Ray::new(vec3f(-0.6, 1.0, 0.01), vec3f(0.0, -1.0, 0.0));
+        assert!((shade_hit(&rpu.preview, &ray, &rpu.context) - 1.1).abs() < 1e-4);
+    }
+}